@@ -16,13 +16,21 @@ import java.net.*
 import java.util.*
 import kotlin.collections.HashMap
 
-class Resolver(private val storage: SqlStorage, private val tracker: InetSocketAddress) {
+/**
+ * @param trackerKey public key of the tracker, if set lookup answers are accepted only with its valid signature
+ */
+class Resolver(private val storage: SqlStorage, private val tracker: InetSocketAddress, private val trackerKey: ByteArray? = null) {
 
     companion object {
         private const val TAG = "Resolver"
         private const val VERSION = 1
         private const val CMD_ANNOUNCE = 0
         private const val CMD_GET_IPS = 1
+        private const val MAX_RESULTS = 8
+        private const val LOOKUP_FLAG_MAX_AGE = 0x04
+        // Half of tracker's address TTL, older addresses are likely from offline clients
+        private const val MAX_AGE_SECS = 1800
+        private const val SIGNATURE_SIZE = 64
     }
 
     private val random = Random(System.currentTimeMillis())
@@ -49,6 +57,15 @@ class Resolver(private val storage: SqlStorage, private val tracker: InetSocketA
                         pair.second.onAnnounceResponse(pair.first, ttl)
                     }
                     CMD_GET_IPS -> {
+                        if (trackerKey != null) {
+                            if (packet.length < SIGNATURE_SIZE) continue
+                            val signed = packet.data.copyOfRange(0, packet.length - SIGNATURE_SIZE)
+                            val signature = packet.data.copyOfRange(packet.length - SIGNATURE_SIZE, packet.length)
+                            if (!Sign.verify(Ed25519PublicKeyParameters(trackerKey), signed, signature)) {
+                                Log.w(TAG, "Wrong tracker signature!")
+                                continue
+                            }
+                        }
                         val count = dis.readByte()
                         Log.i(TAG, "Got $count ips")
                         if (count <= 0) continue
@@ -102,6 +119,9 @@ class Resolver(private val storage: SqlStorage, private val tracker: InetSocketA
         nonces[nonce] = pubkey to receiver
         dos.writeByte(CMD_GET_IPS)
         dos.write(pubkey)
+        dos.writeByte(MAX_RESULTS)
+        dos.writeByte(LOOKUP_FLAG_MAX_AGE)
+        dos.writeInt(MAX_AGE_SECS)
         val request = baos.toByteArray()
         val packet = DatagramPacket(request, request.size, tracker)
         try {
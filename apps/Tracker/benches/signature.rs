@@ -0,0 +1,62 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use rayon::prelude::*;
+use tracker::functions::{check_registration_signature, check_signature};
+use tracker::test_helpers::{generate_keypairs, sign_ip, sign_registration};
+
+const BATCH: usize = 100;
+
+/// Generated keys are the same on every run, so every run verifies the same data
+fn signed_ip() -> ([u8; 32], [u8; 64], [u8; 16]) {
+    let (keypair, public_key) = generate_keypairs(1).remove(0);
+    let mut ip = [0u8; 16];
+    ip[0] = 0x02;
+    ip[15] = 0x01;
+    (public_key, sign_ip(&keypair, ip, 0), ip)
+}
+
+/// Registration signed like clients of `REGISTRATION_SIGNATURE_VERSION` do, with all fields
+fn signed_registration() -> ([u8; 32], [u8; 64], [u8; 16]) {
+    let (keypair, public_key) = generate_keypairs(1).remove(0);
+    let mut ip = [0u8; 16];
+    ip[0] = 0x02;
+    ip[15] = 0x01;
+    (public_key, sign_registration(&keypair, ip, 5050, 1, 1, 1_700_000_000), ip)
+}
+
+fn bench_signature(c: &mut Criterion) {
+    let (public_key, signature, ip) = signed_ip();
+
+    let mut group = c.benchmark_group("check_signature");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("single", |b| {
+        b.iter(|| check_signature(black_box(&public_key), black_box(&signature), black_box(&ip)))
+    });
+
+    group.throughput(Throughput::Elements(BATCH as u64));
+    group.bench_function("sequential_100", |b| {
+        b.iter(|| {
+            for _ in 0..BATCH {
+                black_box(check_signature(black_box(&public_key), black_box(&signature), black_box(&ip)));
+            }
+        })
+    });
+    group.bench_function("parallel_100", |b| {
+        b.iter(|| {
+            (0..BATCH).into_par_iter().for_each(|_| {
+                black_box(check_signature(black_box(&public_key), black_box(&signature), black_box(&ip)));
+            })
+        })
+    });
+    group.finish();
+
+    let (public_key, signature, ip) = signed_registration();
+    let mut group = c.benchmark_group("check_registration_signature");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("single", |b| {
+        b.iter(|| check_registration_signature(black_box(&public_key), black_box(&ip), 5050, 1, 1, 1_700_000_000, black_box(&signature)))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_signature);
+criterion_main!(benches);
@@ -0,0 +1,52 @@
+use std::fs;
+use std::time::{SystemTime, UNIX_EPOCH};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use tracker::functions::to_hex;
+use tracker::storage::{SqliteStorage, Storage};
+
+const IPS: u32 = 1000;
+const IDS_PER_IP: u32 = 100;
+
+/// Both IP and ID are made of a counter, signatures are not checked on import
+fn fill_storage() -> SqliteStorage {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let signature = to_hex(&[0u8; 64]);
+    let mut lines = String::new();
+    for ip in 0..IPS {
+        let mut ip_bytes = [0u8; 16];
+        ip_bytes[0] = 0x02;
+        ip_bytes[12..].copy_from_slice(&ip.to_be_bytes());
+        let ip_hex = to_hex(&ip_bytes);
+        for id in 0..IDS_PER_IP {
+            let mut id_bytes = [0u8; 32];
+            id_bytes[..4].copy_from_slice(&ip.to_be_bytes());
+            id_bytes[4..8].copy_from_slice(&id.to_be_bytes());
+            lines.push_str(&format!(
+                "{{\"id\":\"{}\",\"ip\":\"{}\",\"signature\":\"{}\",\"port\":5050,\"priority\":1,\"client\":1,\"timestamp\":{},\"ttl\":3600}}\n",
+                to_hex(&id_bytes), ip_hex, signature, now
+            ));
+        }
+    }
+    let path = std::env::temp_dir().join("mimir-bench-storage.ndjson");
+    fs::write(&path, lines).unwrap();
+    let storage = SqliteStorage::new_in_memory();
+    storage.import_from_json(path.to_str().unwrap(), true).unwrap();
+    fs::remove_file(&path).unwrap();
+    storage
+}
+
+fn bench_storage(c: &mut Criterion) {
+    let storage = fill_storage();
+    let mut ip = [0u8; 16];
+    ip[0] = 0x02;
+    ip[12..].copy_from_slice(&(IPS / 2).to_be_bytes());
+
+    let mut group = c.benchmark_group("storage_100k");
+    group.bench_function("count_active_ids_for_ip", |b| {
+        b.iter(|| storage.count_active_ids_for_ip(black_box(&ip)))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_storage);
+criterion_main!(benches);
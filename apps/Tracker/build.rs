@@ -0,0 +1,92 @@
+use vergen::EmitBuilder;
+
+#[path = "src/queries.rs"]
+mod queries;
+
+use queries::*;
+
+/// Every statement with the number of values the storage binds to it
+const STATEMENTS: &[(&str, &str, usize)] = &[
+    ("SQL_GET_DB_VERSION", SQL_GET_DB_VERSION, 0),
+    ("SQL_SELECT_SAVED_ROW", SQL_SELECT_SAVED_ROW, 2),
+    ("SQL_UPSERT_IP", SQL_UPSERT_IP, 11),
+    ("SQL_BULK_UPSERT_IP", SQL_BULK_UPSERT_IP, 11),
+    ("SQL_SOFT_DELETE_IP", SQL_SOFT_DELETE_IP, 7),
+    ("SQL_TOUCH_IP", SQL_TOUCH_IP, 5),
+    ("SQL_SELECT_IPS", SQL_SELECT_IPS, 1),
+    ("SQL_SELECT_IPS_LIMITED", SQL_SELECT_IPS_LIMITED, 3),
+    ("SQL_SELECT_IPS_MATCHING", SQL_SELECT_IPS_MATCHING, 8),
+    ("SQL_SELECT_IPS_FOR_IDS", SQL_SELECT_IPS_FOR_IDS, 2),
+    ("SQL_SELECT_ALL_IPS", SQL_SELECT_ALL_IPS, 0),
+    ("SQL_DELETE_ADDRESS", SQL_DELETE_ADDRESS, 3),
+    ("SQL_DELETE_ID", SQL_DELETE_ID, 1),
+    ("SQL_SELECT_IDS", SQL_SELECT_IDS, 2),
+    ("SQL_SELECT_IDS_AFTER", SQL_SELECT_IDS_AFTER, 2),
+    ("SQL_COUNT_IDS_FOR_IP", SQL_COUNT_IDS_FOR_IP, 2),
+    ("SQL_COUNT_ADDRESSES_FOR_ID", SQL_COUNT_ADDRESSES_FOR_ID, 2),
+    ("SQL_COUNT_TOTAL", SQL_COUNT_TOTAL, 0),
+    ("SQL_DELETE_EXPIRED", SQL_DELETE_EXPIRED, 2),
+    ("SQL_INSERT_TOMBSTONE", SQL_INSERT_TOMBSTONE, 4),
+    ("SQL_DELETE_TOMBSTONED", SQL_DELETE_TOMBSTONED, 3),
+    ("SQL_UPSERT_BAN", SQL_UPSERT_BAN, 4),
+    ("SQL_SELECT_BAN", SQL_SELECT_BAN, 2),
+    ("SQL_SELECT_ACTIVE_BANS", SQL_SELECT_ACTIVE_BANS, 1),
+    ("SQL_DELETE_EXPIRED_BANS", SQL_DELETE_EXPIRED_BANS, 1),
+    ("SQL_UPSERT_REJECTED_ID", SQL_UPSERT_REJECTED_ID, 3),
+    ("SQL_SELECT_REJECTED_IDS", SQL_SELECT_REJECTED_IDS, 0),
+    ("SQL_SELECT_SETTING", SQL_SELECT_SETTING, 1),
+    ("SQL_UPSERT_SETTING", SQL_UPSERT_SETTING, 2),
+    ("SQL_SELECT_TOMBSTONES_SINCE", SQL_SELECT_TOMBSTONES_SINCE, 1),
+    ("SQL_DELETE_OLD_TOMBSTONES", SQL_DELETE_OLD_TOMBSTONES, 1),
+    ("SQL_BEGIN", SQL_BEGIN, 0),
+    ("SQL_BEGIN_IMMEDIATE", SQL_BEGIN_IMMEDIATE, 0),
+    ("SQL_COMMIT", SQL_COMMIT, 0),
+    ("SQL_ROLLBACK", SQL_ROLLBACK, 0),
+];
+
+/// Multi-statement scripts without parameters, they are executed as is
+const SCRIPTS: &[(&str, &str)] = &[
+    ("SQL_VACUUM", SQL_VACUUM),
+    ("SQL_INCREMENTAL_VACUUM", SQL_INCREMENTAL_VACUUM),
+    ("SQL_WAL_CHECKPOINT", SQL_WAL_CHECKPOINT),
+];
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed=src/queries.rs");
+    println!("cargo:rerun-if-changed=src/create_db.sql");
+    check_queries()?;
+
+    // Emits VERGEN_BUILD_TIMESTAMP and VERGEN_GIT_SHA for the Version struct
+    EmitBuilder::builder()
+        .build_timestamp()
+        .git_sha(true)
+        .emit()?;
+    Ok(())
+}
+
+/// Prepares all statements against a fresh schema, so that typos in SQL fail the build, not the server
+fn check_queries() -> Result<(), String> {
+    let db = sqlite::open(":memory:").map_err(|e| format!("Unable to open sqlite DB: {}", e))?;
+    db.execute(SQL_CREATE_TABLES).map_err(|e| format!("Error in SQL_CREATE_TABLES: {}", e))?;
+    for (index, migration) in MIGRATIONS.iter().enumerate() {
+        db.execute(migration).map_err(|e| format!("Error in migration {}: {}", index, e))?;
+    }
+    for (name, sql, binds) in STATEMENTS {
+        let placeholders = sql.matches('?').count();
+        if placeholders != *binds {
+            return Err(format!("{} has {} placeholders, but {} values are bound", name, placeholders, binds));
+        }
+        let mut statement = db.prepare(*sql).map_err(|e| format!("Error in {}: {}", name, e))?;
+        // Placeholders inside string literals would be counted above, but SQLite doesn't see them
+        if *binds > 0 && statement.bind((*binds, 0i64)).is_err() {
+            return Err(format!("{} takes less than {} values", name, binds));
+        }
+        if statement.bind((*binds + 1, 0i64)).is_ok() {
+            return Err(format!("{} takes more than {} values", name, binds));
+        }
+    }
+    for (name, sql) in SCRIPTS {
+        db.execute(*sql).map_err(|e| format!("Error in {}: {}", name, e))?;
+    }
+    Ok(())
+}
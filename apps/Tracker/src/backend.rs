@@ -0,0 +1,39 @@
+use std::str::FromStr;
+use crate::error::MimirError;
+use crate::storage::{SqliteStorage, Storage};
+
+/// Storage implementation selected by name, so users don't need to know which features are compiled in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageBackend {
+    /// SQLite database file at `db_path`
+    Sqlite,
+    /// SQLite database in memory, nothing is persisted
+    Memory
+}
+
+impl FromStr for StorageBackend {
+    type Err = MimirError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sqlite" => Ok(StorageBackend::Sqlite),
+            "memory" => Ok(StorageBackend::Memory),
+            _ => Err(MimirError::UnsupportedBackend(s.to_owned()))
+        }
+    }
+}
+
+impl StorageBackend {
+    /// Checks settings of this backend and opens it, `db_path` is used by `Sqlite` only
+    pub fn open(&self, db_path: &str) -> Result<Box<dyn Storage>, MimirError> {
+        match self {
+            StorageBackend::Sqlite => {
+                if db_path.is_empty() {
+                    return Err(MimirError::InvalidData(String::from("sqlite backend needs a DB path")));
+                }
+                Ok(Box::new(SqliteStorage::new(db_path)))
+            }
+            StorageBackend::Memory => Ok(Box::new(SqliteStorage::new_in_memory()))
+        }
+    }
+}
@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, Ipv6Addr};
+use std::num::NonZeroUsize;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use lru::LruCache;
+use tracing::warn;
+use crate::error::MimirError;
+use crate::storage::{get_utc_time, Ban, Storage};
+
+/// Bans added from command line last this long
+pub const DEFAULT_BAN_SECS: u64 = 86400;
+/// IPs found not banned in storage are not looked up again for this long,
+/// so bans added to the database by other tools take effect after it
+const NOT_BANNED_SECS: u64 = 60;
+/// At most this many IPs are remembered as not banned, the ones seen longest ago are forgotten first
+const MAX_NOT_BANNED_IPS: usize = 65536;
+
+/// IP or subnet of a ban, IPv4 ones are kept as IPv4-mapped IPv6 to match both kinds of sockets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNet {
+    addr: Ipv6Addr,
+    prefix: u8
+}
+
+impl IpNet {
+    /// Network of this one IP
+    pub fn host(ip: IpAddr) -> Self {
+        IpNet { addr: to_ipv6(ip), prefix: 128 }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let mask = mask(self.prefix);
+        u128::from(to_ipv6(ip)) & mask == u128::from(self.addr) & mask
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = MimirError;
+
+    /// Parses `ip/prefix` or a single `ip`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ip, prefix) = match s.split_once('/') {
+            Some((ip, prefix)) => (ip, Some(prefix)),
+            None => (s, None)
+        };
+        let ip: IpAddr = ip.parse().map_err(|_| MimirError::InvalidData(format!("wrong IP in {}", s)))?;
+        let max_prefix = if ip.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().ok().filter(|prefix| *prefix <= max_prefix),
+            None => Some(max_prefix)
+        };
+        let prefix = prefix.ok_or_else(|| MimirError::InvalidData(format!("wrong prefix length in {}", s)))?;
+        let prefix = prefix + (128 - max_prefix);
+        let addr = Ipv6Addr::from(u128::from(to_ipv6(ip)) & mask(prefix));
+        Ok(IpNet { addr, prefix })
+    }
+}
+
+impl Display for IpNet {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+struct CachedBan {
+    net: IpNet,
+    expires_at: u64
+}
+
+/// Banned IPs and subnets, saved in storage so that bans survive restarts
+pub struct BanList {
+    storage: Arc<dyn Storage>,
+    /// Active bans by `ip_cidr`
+    bans: Mutex<HashMap<String, CachedBan>>,
+    /// IPs without a ban in storage, with UTC time until they are not looked up again
+    not_banned: Mutex<LruCache<IpAddr, u64>>
+}
+
+impl BanList {
+    /// Loads all not expired bans from storage
+    pub fn load_all_active(storage: Arc<dyn Storage>) -> Self {
+        let mut bans = HashMap::new();
+        for ban in storage.get_active_bans() {
+            match ban.ip_cidr.parse() {
+                Ok(net) => {
+                    bans.insert(ban.ip_cidr, CachedBan { net, expires_at: ban.expires_at });
+                }
+                Err(e) => warn!("Ignoring saved ban: {}", e)
+            }
+        }
+        let not_banned = Mutex::new(LruCache::new(NonZeroUsize::new(MAX_NOT_BANNED_IPS).unwrap()));
+        BanList { storage, bans: Mutex::new(bans), not_banned }
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let now = get_utc_time();
+        if self.bans.lock().unwrap().values().any(|ban| ban.expires_at > now && ban.net.contains(ip)) {
+            return true;
+        }
+        // Checked before storage, so that floods from one IP don't wait for the database
+        if self.not_banned.lock().unwrap().get(&ip).is_some_and(|until| *until > now) {
+            return false;
+        }
+        // Bans of single IPs can be added to the database by other tools while the server runs
+        let host = IpNet::host(ip);
+        match self.storage.get_ban(&host.to_string()) {
+            Some(ban) => {
+                self.bans.lock().unwrap().insert(ban.ip_cidr, CachedBan { net: host, expires_at: ban.expires_at });
+                true
+            }
+            None => {
+                self.not_banned.lock().unwrap().put(ip, now + NOT_BANNED_SECS);
+                false
+            }
+        }
+    }
+
+    /// Bans an IP or subnet like `200:1234::/48` for `duration_secs`, replacing its previous ban
+    pub fn ban(&self, ip_cidr: &str, duration_secs: u64, reason: &str) -> Result<(), MimirError> {
+        let net: IpNet = ip_cidr.parse()?;
+        let now = get_utc_time();
+        let ban = Ban { ip_cidr: net.to_string(), banned_at: now, expires_at: now + duration_secs, reason: reason.to_owned() };
+        self.storage.add_ban(&ban)?;
+        let mut bans = self.bans.lock().unwrap();
+        // Storage removes expired bans in `cleanup_expired`
+        bans.retain(|_, ban| ban.expires_at > now);
+        bans.insert(ban.ip_cidr, CachedBan { net, expires_at: ban.expires_at });
+        Ok(())
+    }
+}
+
+/// Mask with `prefix` highest bits set
+fn mask(prefix: u8) -> u128 {
+    u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0)
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use crate::storage::SqliteStorage;
+
+    fn ban_list() -> BanList {
+        BanList::load_all_active(Arc::new(SqliteStorage::new_in_memory()))
+    }
+
+    #[test]
+    fn parses_subnets_and_hosts() {
+        let net: IpNet = "200:1234::ffff/48".parse().unwrap();
+        assert_eq!(net.to_string(), "200:1234::/48");
+        assert!(net.contains("200:1234:0:1::1".parse().unwrap()));
+        assert!(!net.contains("200:1235::1".parse().unwrap()));
+        assert_eq!("::1".parse::<IpNet>().unwrap(), IpNet::host(IpAddr::V6(Ipv6Addr::LOCALHOST)));
+        assert!("::/0".parse::<IpNet>().unwrap().contains("2001:db8::1".parse().unwrap()));
+        for wrong in ["200:1234::/129", "10.0.0.0/33", "200:1234::/x", "200:zz::/48", ""] {
+            assert!(wrong.parse::<IpNet>().is_err(), "{}", wrong);
+        }
+    }
+
+    #[test]
+    fn ipv4_nets_match_mapped_addresses() {
+        let net: IpNet = "10.1.2.3/16".parse().unwrap();
+        assert_eq!(net.to_string(), "::ffff:10.1.0.0/112");
+        assert!(net.contains(IpAddr::V4(Ipv4Addr::new(10, 1, 200, 1))));
+        assert!(net.contains(IpAddr::V6(Ipv4Addr::new(10, 1, 200, 1).to_ipv6_mapped())));
+        assert!(!net.contains(IpAddr::V4(Ipv4Addr::new(10, 2, 0, 1))));
+    }
+
+    #[test]
+    fn bans_expire() {
+        let bans = ban_list();
+        let ip = "200:1234::1".parse().unwrap();
+        bans.ban("200:1234::/48", 0, "test").unwrap();
+        assert!(!bans.is_banned(ip));
+        bans.ban("200:1234::/48", 60, "test").unwrap();
+        assert!(bans.is_banned(ip));
+        assert!(!bans.is_banned("200:1235::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn saved_bans_are_loaded() {
+        let storage: Arc<dyn Storage> = Arc::new(SqliteStorage::new_in_memory());
+        BanList::load_all_active(Arc::clone(&storage)).ban("200:1234::/48", 60, "test").unwrap();
+        assert!(BanList::load_all_active(storage).is_banned("200:1234::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn storage_is_not_asked_again_for_not_banned_ip() {
+        let bans = ban_list();
+        let (banned, other) = ("200:1234::1".parse().unwrap(), "200:1234::2".parse().unwrap());
+        let now = get_utc_time();
+        // Like bans added by other tools while the server runs
+        let ban = |ip: IpAddr| Ban { ip_cidr: IpNet::host(ip).to_string(), banned_at: now, expires_at: now + 60, reason: String::from("tool") };
+        bans.storage.add_ban(&ban(banned)).unwrap();
+        assert!(bans.is_banned(banned));
+
+        assert!(!bans.is_banned(other));
+        bans.storage.add_ban(&ban(other)).unwrap();
+        assert!(!bans.is_banned(other));
+        // Until the saved answer expires
+        bans.not_banned.lock().unwrap().put(other, now);
+        assert!(bans.is_banned(other));
+    }
+}
@@ -0,0 +1,212 @@
+//! Decodes HEX dumps of tracker packets from stdin, one packet per line, and prints their fields
+use std::env;
+use std::io::{self, BufRead};
+use std::net::Ipv6Addr;
+use std::process::exit;
+use tracker::error::MimirError;
+use tracker::functions::{from_hex, to_hex};
+use tracker::packet::SafeCursor;
+use tracker::protocol::*;
+use tracker::server::ErrorCode;
+use tracker::version::{ADDR_FLAGS_VERSION, LATENCY_HINT_VERSION, PROTOCOL_VERSION, REQUEST_TIMESTAMP_VERSION};
+
+fn main() {
+    let mut responses = false;
+    let mut version = PROTOCOL_VERSION;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--responses" => responses = true,
+            "--version" => match args.next().and_then(|v| v.parse().ok()) {
+                Some(v) => version = v,
+                None => {
+                    println!("Wrong --version value");
+                    exit(1);
+                }
+            },
+            _ => {
+                // Answers don't carry the version, it is needed to know the layout of addresses
+                println!("Usage: ./mimir-decode [--responses [--version n]] < packets.hex");
+                exit(0);
+            }
+        }
+    }
+    for line in io::stdin().lock().lines() {
+        let line = line.expect("Error reading stdin");
+        // Dumps of `xxd -p` and similar tools can be split in groups
+        let hex: String = line.split_whitespace().collect();
+        if hex.is_empty() {
+            continue;
+        }
+        let Some(packet) = from_hex(&hex) else {
+            println!("[ERROR] not a HEX string: {}", line);
+            continue;
+        };
+        let mut fields = Vec::new();
+        let result = match responses {
+            true => decode_response(&packet, version, &mut fields),
+            false => decode_request(&packet, &mut fields)
+        };
+        match result {
+            Ok(()) => println!("{}", fields.join(", ")),
+            Err(MimirError::MalformedPacket { position }) => println!("[ERROR] position={}: MalformedPacket", position),
+            Err(e) => println!("[ERROR] {}", e)
+        }
+    }
+}
+
+fn decode_request(packet: &[u8], fields: &mut Vec<String>) -> Result<(), MimirError> {
+    let mut c = SafeCursor::new(packet);
+    let version = c.read_u8()?;
+    fields.push(format!("version={}", version));
+    fields.push(format!("nonce={}", c.read_u32_be()?));
+    if version >= REQUEST_TIMESTAMP_VERSION {
+        fields.push(format!("timestamp={}", c.read_u32_be()?));
+    }
+    let command = Command::from(c.read_u8()?);
+    fields.push(format!("command={:?}", command));
+    fields.push(format!("id={}", to_hex(&c.read_array::<32>()?)));
+    match command {
+        Command::Register | Command::Deregister => {
+            let port = c.read_u16_be()?;
+            let priority = c.read_u8()?;
+            let client = c.read_u32_be()?;
+            fields.push(format!("ip=[{}]", Ipv6Addr::from(c.read_array::<16>()?)));
+            fields.push(format!("port={}", port));
+            fields.push(format!("priority={}", priority));
+            fields.push(format!("client={}", client));
+            fields.push(format!("signature={}", to_hex(&c.read_array::<64>()?)));
+            if command == Command::Register && c.remaining() > 0 {
+                fields.push(format!("latency_hint_ms={}", c.read_u16_be()?));
+                if c.remaining() > 0 {
+                    fields.push(format!("register_flags={:#04x}", c.read_u8()?));
+                }
+            }
+            if command == Command::Deregister && c.remaining() > 0 {
+                let flags = c.read_u8()?;
+                fields.push(format!("flags={:#04x}", flags));
+                if flags & FLAG_TOMBSTONE != 0 {
+                    fields.push(format!("deleted_at={}", c.read_u64_be()?));
+                    fields.push(format!("tombstone_signature={}", to_hex(&c.read_array::<64>()?)));
+                }
+            }
+        }
+        Command::Lookup => {
+            if c.remaining() > 0 {
+                fields.push(format!("max_results={}", c.read_u8()?));
+            }
+            let flags = if c.remaining() > 0 { c.read_u8()? } else { 0 };
+            if flags != 0 {
+                fields.push(format!("flags={:#04x}", flags));
+            }
+            if flags & LOOKUP_FLAG_CLIENT != 0 {
+                fields.push(format!("filter_client={}", c.read_u32_be()?));
+            }
+            if flags & LOOKUP_FLAG_MAX_AGE != 0 {
+                fields.push(format!("max_age_secs={}", c.read_u32_be()?));
+            }
+            if flags & LOOKUP_FLAG_CLIENT_IP != 0 {
+                fields.push(format!("client_ip=[{}]", Ipv6Addr::from(c.read_array::<16>()?)));
+            }
+            if flags & LOOKUP_FLAG_PRIORITY != 0 {
+                fields.push(format!("priority_range={}..={}", c.read_u8()?, c.read_u8()?));
+            }
+            if flags & LOOKUP_FLAG_QUERIER_ID != 0 {
+                fields.push(format!("querier_id={}", to_hex(&c.read_array::<32>()?)));
+            }
+        }
+        Command::BatchLookup => {
+            let count = c.read_u8()?;
+            fields.push(format!("count={}", count));
+            for index in 0..count {
+                fields.push(format!("ids[{}]={}", index, to_hex(&c.read_array::<32>()?)));
+            }
+        }
+        _ => {}
+    }
+    push_trailing(&c, packet, fields);
+    Ok(())
+}
+
+fn decode_response(packet: &[u8], version: u8, fields: &mut Vec<String>) -> Result<(), MimirError> {
+    let mut c = SafeCursor::new(packet);
+    fields.push(format!("nonce={}", c.read_u32_be()?));
+    let command = c.read_u8()?;
+    if command == CMD_ERROR {
+        let code = c.read_u8()?;
+        let name = match code {
+            code if code == ErrorCode::UnsupportedVersion as u8 => "UnsupportedVersion",
+            code if code == ErrorCode::RegistrationRejected as u8 => "RegistrationRejected",
+            code if code == ErrorCode::RateLimited as u8 => "RateLimited",
+            _ => "Unknown"
+        };
+        fields.push(format!("error={}({})", name, code));
+        push_trailing(&c, packet, fields);
+        return Ok(());
+    }
+    let command = Command::from(command);
+    fields.push(format!("command={:?}", command));
+    match command {
+        Command::Register | Command::Deregister => fields.push(format!("ttl={}", c.read_u64_be()?)),
+        Command::Lookup => {
+            let count = c.read_u8()?;
+            fields.push(format!("count={}", count));
+            for index in 0..count {
+                fields.push(format!("addr[{}]={}", index, decode_addr(&mut c, version)?));
+            }
+        }
+        Command::BatchLookup => {
+            fields.push(format!("flags={:#04x}", c.read_u8()?));
+            let groups = c.read_u8()?;
+            fields.push(format!("ids={}", groups));
+            for group in 0..groups {
+                let count = c.read_u8()?;
+                fields.push(format!("ids[{}].count={}", group, count));
+                for index in 0..count {
+                    fields.push(format!("ids[{}].addr[{}]={}", group, index, decode_addr(&mut c, version)?));
+                }
+            }
+        }
+        Command::Ping => {
+            fields.push(format!("max_protocol_version={}", c.read_u8()?));
+            // Length byte and the crate version of the tracker, like "0.1.1"
+            let length = c.read_u8()? as usize;
+            fields.push(format!("crate_version={}", String::from_utf8_lossy(c.read_bytes(length)?)));
+            if c.remaining() > 0 {
+                fields.push(format!("tracker_key={}", to_hex(&c.read_array::<32>()?)));
+            }
+        }
+        _ => {}
+    }
+    push_trailing(&c, packet, fields);
+    Ok(())
+}
+
+/// Reads one address of lookup answers, its layout depends on the protocol version
+fn decode_addr(c: &mut SafeCursor, version: u8) -> Result<String, MimirError> {
+    let ip = Ipv6Addr::from(c.read_array::<16>()?);
+    let _signature: [u8; 64] = c.read_array()?;
+    let port = c.read_u16_be()?;
+    let priority = c.read_u8()?;
+    let client = c.read_u32_be()?;
+    let ttl = c.read_u64_be()?;
+    let mut addr = format!("{{ip=[{}], port={}, priority={}, client={}, ttl={}", ip, port, priority, client, ttl);
+    if version >= LATENCY_HINT_VERSION {
+        addr.push_str(&format!(", latency_hint_ms={}", c.read_u16_be()?));
+    }
+    if version >= ADDR_FLAGS_VERSION {
+        addr.push_str(&format!(", flags={:#04x}", c.read_u8()?));
+    }
+    if version >= REQUEST_TIMESTAMP_VERSION {
+        addr.push_str(&format!(", signed_at={}", c.read_u32_be()?));
+    }
+    addr.push('}');
+    Ok(addr)
+}
+
+/// Shows bytes after the known fields, like the tracker signature of command-1 answers
+fn push_trailing(c: &SafeCursor, packet: &[u8], fields: &mut Vec<String>) {
+    if c.remaining() > 0 {
+        fields.push(format!("trailing={}", to_hex(&packet[c.position()..])));
+    }
+}
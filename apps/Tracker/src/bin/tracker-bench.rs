@@ -0,0 +1,180 @@
+//! Load test of a running tracker, sends registrations and lookups at fixed rates and reports latencies
+use std::collections::HashMap;
+use std::env;
+use std::net::{SocketAddr, UdpSocket};
+use std::process::exit;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signer};
+use tracker::protocol::Command;
+use tracker::storage::get_utc_time;
+use tracker::version::PROTOCOL_VERSION;
+
+const CMD_REGISTER: u8 = Command::Register.byte();
+const CMD_GET_IPS: u8 = Command::Lookup.byte();
+/// Answers that don't come in this time after the test are counted as lost
+const DRAIN_TIME: Duration = Duration::from_secs(1);
+
+struct Options {
+    tracker: SocketAddr,
+    num_ids: u32,
+    registrations_per_sec: u32,
+    lookups_per_sec: u32,
+    duration_secs: u64
+}
+
+/// Requests waiting for answers by nonce, with the time they were sent
+type Pending = Mutex<HashMap<u32, (u8, Instant)>>;
+
+fn main() {
+    let options = parse_options();
+    // Keys are derived from the index, so repeated runs update the same addresses
+    let keys: Arc<Vec<Keypair>> = Arc::new((0..options.num_ids).map(keypair).collect());
+    let socket = UdpSocket::bind(if options.tracker.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }).expect("Unable to bind socket");
+    socket.set_read_timeout(Some(Duration::from_millis(100))).expect("Error setting timeout");
+    let socket = Arc::new(socket);
+    let pending: Arc<Pending> = Arc::new(Mutex::new(HashMap::new()));
+    let nonce = Arc::new(AtomicU32::new(get_utc_time() as u32));
+    let running = Arc::new(AtomicBool::new(true));
+
+    let receiver = {
+        let (socket, pending, running) = (Arc::clone(&socket), Arc::clone(&pending), Arc::clone(&running));
+        thread::spawn(move || receive(&socket, &pending, &running))
+    };
+    let start = Instant::now();
+    let deadline = start + Duration::from_secs(options.duration_secs);
+    let senders: Vec<_> = [(CMD_REGISTER, options.registrations_per_sec), (CMD_GET_IPS, options.lookups_per_sec)]
+        .into_iter()
+        .filter(|(_, rate)| *rate > 0)
+        .map(|(command, rate)| {
+            let (socket, pending, nonce, keys) = (Arc::clone(&socket), Arc::clone(&pending), Arc::clone(&nonce), Arc::clone(&keys));
+            let tracker = options.tracker;
+            thread::spawn(move || send(&socket, tracker, &pending, &nonce, &keys, command, rate, deadline))
+        })
+        .collect();
+    let sent: u64 = senders.into_iter().map(|sender| sender.join().unwrap()).sum();
+    let elapsed = start.elapsed();
+    thread::sleep(DRAIN_TIME);
+    running.store(false, Ordering::Relaxed);
+    let mut latencies = receiver.join().unwrap();
+    let lost = pending.lock().unwrap().len();
+    report(&mut latencies, sent, lost, elapsed);
+}
+
+fn parse_options() -> Options {
+    let mut tracker = None;
+    let mut num_ids = 1000;
+    let mut registrations_per_sec = 100;
+    let mut lookups_per_sec = 1000;
+    let mut duration_secs = 10;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = args.next();
+        let ok = match arg.as_str() {
+            "--tracker" => value.as_ref().and_then(|v| v.parse().ok()).map(|v| tracker = Some(v)).is_some(),
+            "--num-ids" => value.as_ref().and_then(|v| v.parse().ok()).filter(|v| *v > 0).map(|v| num_ids = v).is_some(),
+            "--registrations-per-sec" => value.as_ref().and_then(|v| v.parse().ok()).map(|v| registrations_per_sec = v).is_some(),
+            "--lookups-per-sec" => value.as_ref().and_then(|v| v.parse().ok()).map(|v| lookups_per_sec = v).is_some(),
+            "--duration-secs" => value.as_ref().and_then(|v| v.parse().ok()).map(|v| duration_secs = v).is_some(),
+            _ => false
+        };
+        if !ok {
+            println!("Wrong {} value: {}", arg, value.unwrap_or_default());
+            exit(1);
+        }
+    }
+    match tracker {
+        Some(tracker) => Options { tracker, num_ids, registrations_per_sec, lookups_per_sec, duration_secs },
+        None => {
+            println!("Usage: ./tracker-bench --tracker [IPv6]:port [--num-ids n] [--registrations-per-sec n] [--lookups-per-sec n] [--duration-secs secs]");
+            exit(0);
+        }
+    }
+}
+
+fn keypair(index: u32) -> Keypair {
+    let mut seed = [0x4du8; 32];
+    seed[..4].copy_from_slice(&index.to_be_bytes());
+    let secret = SecretKey::from_bytes(&seed).unwrap();
+    let public = PublicKey::from(&secret);
+    Keypair { secret, public }
+}
+
+/// Sends requests of one command at `rate` per second until `deadline`, returns the number of sent ones
+#[allow(clippy::too_many_arguments)]
+fn send(socket: &UdpSocket, tracker: SocketAddr, pending: &Pending, nonce: &AtomicU32, keys: &[Keypair], command: u8, rate: u32, deadline: Instant) -> u64 {
+    let interval = Duration::from_secs_f64(1.0 / rate as f64);
+    let mut next = Instant::now();
+    let mut sent = 0u64;
+    while next < deadline {
+        let key = &keys[sent as usize % keys.len()];
+        let nonce = nonce.fetch_add(1, Ordering::Relaxed);
+        let packet = build_request(key, nonce, command, sent as u32);
+        pending.lock().unwrap().insert(nonce, (command, Instant::now()));
+        if let Err(e) = socket.send_to(&packet, tracker) {
+            println!("Error sending to {}: {}", tracker, e);
+        }
+        sent += 1;
+        // Sleeping to the next slot, not for the interval, keeps the rate when sending is slow
+        next += interval;
+        if let Some(delay) = next.checked_duration_since(Instant::now()) {
+            thread::sleep(delay);
+        }
+    }
+    sent
+}
+
+fn build_request(key: &Keypair, nonce: u32, command: u8, counter: u32) -> Vec<u8> {
+    let timestamp = get_utc_time() as u32;
+    let mut packet = Vec::with_capacity(128);
+    packet.push(PROTOCOL_VERSION);
+    packet.extend_from_slice(&nonce.to_be_bytes());
+    packet.extend_from_slice(&timestamp.to_be_bytes());
+    packet.push(command);
+    packet.extend_from_slice(key.public.as_bytes());
+    if command == CMD_REGISTER {
+        let mut ip = [0u8; 16];
+        ip[0] = 0x02;
+        ip[12..].copy_from_slice(&counter.to_be_bytes());
+        let mut signed = timestamp.to_be_bytes().to_vec();
+        signed.extend_from_slice(&ip);
+        packet.extend_from_slice(&5050u16.to_be_bytes());
+        packet.push(1);
+        packet.extend_from_slice(&1u32.to_be_bytes());
+        packet.extend_from_slice(&ip);
+        packet.extend_from_slice(&key.sign(&signed).to_bytes());
+    }
+    packet
+}
+
+/// Collects round-trip times of answered requests by command, until `running` is cleared
+fn receive(socket: &UdpSocket, pending: &Pending, running: &AtomicBool) -> HashMap<u8, Vec<Duration>> {
+    let mut latencies: HashMap<u8, Vec<Duration>> = HashMap::new();
+    let mut buf = [0u8; 2048];
+    while running.load(Ordering::Relaxed) {
+        let Ok((length, _)) = socket.recv_from(&mut buf) else { continue };
+        if length < 4 {
+            continue;
+        }
+        let nonce = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+        if let Some((command, sent_at)) = pending.lock().unwrap().remove(&nonce) {
+            latencies.entry(command).or_default().push(sent_at.elapsed());
+        }
+    }
+    latencies
+}
+
+fn report(latencies: &mut HashMap<u8, Vec<Duration>>, sent: u64, lost: usize, elapsed: Duration) {
+    let answered: usize = latencies.values().map(Vec::len).sum();
+    println!("Sent {} requests in {:.1?}, {:.0} requests/sec", sent, elapsed, sent as f64 / elapsed.as_secs_f64());
+    println!("Answered {}, lost {}, {:.0} answers/sec", answered, lost, answered as f64 / elapsed.as_secs_f64());
+    for (command, name) in [(CMD_REGISTER, "register"), (CMD_GET_IPS, "lookup")] {
+        let Some(times) = latencies.get_mut(&command).filter(|times| !times.is_empty()) else { continue };
+        times.sort();
+        let percentile = |p: usize| times[(times.len() * p / 100).min(times.len() - 1)];
+        println!("{:>8}: p50 {:>9.2?}  p95 {:>9.2?}  p99 {:>9.2?}  max {:>9.2?}",
+                 name, percentile(50), percentile(95), percentile(99), times[times.len() - 1]);
+    }
+}
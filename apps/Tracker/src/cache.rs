@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use lru::LruCache;
+use crate::error::MimirError;
+use crate::storage::{Addr, AddressFilter, Ban, ClientId, Priority, Registration, RegistrationAction, RegistrationResult, Storage, Tombstone};
+
+struct CacheEntry {
+    addrs: Vec<Addr>,
+    fetched_at: Instant
+}
+
+/// Keeps results of `get_addresses` of the inner storage for `freshness_secs`
+pub struct CachedStorage<S: Storage> {
+    inner: S,
+    cache: Mutex<LruCache<Vec<u8>, CacheEntry>>,
+    freshness: Duration,
+    hits: AtomicU64,
+    misses: AtomicU64
+}
+
+impl<S: Storage> CachedStorage<S> {
+    pub fn new(inner: S, capacity: usize, freshness_secs: u64) -> Self {
+        let capacity = NonZeroUsize::new(capacity).expect("Cache capacity must not be zero");
+        CachedStorage {
+            inner,
+            cache: Mutex::new(LruCache::new(capacity)),
+            freshness: Duration::from_secs(freshness_secs),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0)
+        }
+    }
+
+    /// Returns the share of `get_addresses` calls answered from cache, from 0.0 to 1.0
+    pub fn cache_hit_rate(&self) -> f64 {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let total = hits + self.misses.load(Ordering::Relaxed);
+        if total == 0 {
+            return 0.0;
+        }
+        hits as f64 / total as f64
+    }
+
+    fn invalidate(&self, id: &[u8]) {
+        self.cache.lock().unwrap().pop(id);
+    }
+}
+
+impl<S: Storage> Storage for CachedStorage<S> {
+    fn save_address(&self, id: &[u8], registration: &Registration, soft_delete: bool) -> u64 {
+        self.invalidate(id);
+        self.inner.save_address(id, registration, soft_delete)
+    }
+
+    fn register_or_skip(&self, id: &[u8], registration: &Registration, new_ttl: u64, max_addresses: Option<u64>) -> RegistrationResult {
+        let result = self.inner.register_or_skip(id, registration, new_ttl, max_addresses);
+        if matches!(result.action, RegistrationAction::Inserted | RegistrationAction::Updated) {
+            self.invalidate(id);
+        }
+        result
+    }
+
+    fn touch(&self, id: &[u8], ip: &[u8], client: ClientId) -> Option<u64> {
+        self.invalidate(id);
+        self.inner.touch(id, ip, client)
+    }
+
+    fn get_addresses(&self, id: &[u8]) -> Vec<Addr> {
+        if let Some(entry) = self.cache.lock().unwrap().get(id) {
+            if entry.fetched_at.elapsed() < self.freshness {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return entry.addrs.clone();
+            }
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let addrs = self.inner.get_addresses(id);
+        let entry = CacheEntry { addrs: addrs.clone(), fetched_at: Instant::now() };
+        self.cache.lock().unwrap().put(id.to_vec(), entry);
+        addrs
+    }
+
+    fn get_peer_addresses(&self, ids: &[[u8; 32]]) -> HashMap<[u8; 32], Vec<Addr>> {
+        self.inner.get_peer_addresses(ids)
+    }
+
+    fn get_addresses_filtered(&self, id: &[u8], max_results: u8) -> Vec<Addr> {
+        self.inner.get_addresses_filtered(id, max_results)
+    }
+
+    fn get_addresses_for_client(&self, id: &[u8], client: ClientId) -> Vec<Addr> {
+        self.inner.get_addresses_for_client(id, client)
+    }
+
+    fn get_addresses_recent(&self, id: &[u8], max_age_secs: u64) -> Vec<Addr> {
+        self.inner.get_addresses_recent(id, max_age_secs)
+    }
+
+    fn get_addresses_by_priority_range(&self, id: &[u8], min_priority: Priority, max_priority: Priority) -> Vec<Addr> {
+        self.inner.get_addresses_by_priority_range(id, min_priority, max_priority)
+    }
+
+    fn find_addresses(&self, id: &[u8], filter: &AddressFilter) -> Vec<Addr> {
+        self.inner.find_addresses(id, filter)
+    }
+
+    fn remove_address(&self, id: &[u8], ip: &[u8], client: ClientId) -> bool {
+        self.invalidate(id);
+        self.inner.remove_address(id, ip, client)
+    }
+
+    fn prune_id(&self, id: &[u8]) -> u64 {
+        self.invalidate(id);
+        self.inner.prune_id(id)
+    }
+
+    fn get_all_ids(&self, page: u32, page_size: u32) -> Vec<Vec<u8>> {
+        self.inner.get_all_ids(page, page_size)
+    }
+
+    fn list_ids_paginated(&self, cursor: Option<Vec<u8>>, limit: usize) -> (Vec<Vec<u8>>, Option<Vec<u8>>) {
+        self.inner.list_ids_paginated(cursor, limit)
+    }
+
+    fn count_total(&self) -> (u64, u64) {
+        self.inner.count_total()
+    }
+
+    fn count_active_ids_for_ip(&self, ip: &[u8]) -> u64 {
+        self.inner.count_active_ids_for_ip(ip)
+    }
+
+    fn count_addresses_for_id(&self, id: &[u8]) -> u64 {
+        self.inner.count_addresses_for_id(id)
+    }
+
+    fn add_tombstone(&self, tombstone: &Tombstone) -> bool {
+        self.invalidate(&tombstone.id);
+        self.inner.add_tombstone(tombstone)
+    }
+
+    fn get_tombstones_since(&self, since: u64) -> Vec<Tombstone> {
+        self.inner.get_tombstones_since(since)
+    }
+
+    fn add_ban(&self, ban: &Ban) -> Result<(), MimirError> {
+        self.inner.add_ban(ban)
+    }
+
+    fn get_ban(&self, ip_cidr: &str) -> Option<Ban> {
+        self.inner.get_ban(ip_cidr)
+    }
+
+    fn get_active_bans(&self) -> Vec<Ban> {
+        self.inner.get_active_bans()
+    }
+
+    fn add_rejected_id(&self, id: &[u8], rejected_at: u64, reason: &str) -> Result<(), MimirError> {
+        self.inner.add_rejected_id(id, rejected_at, reason)
+    }
+
+    fn get_rejected_ids(&self) -> Vec<Vec<u8>> {
+        self.inner.get_rejected_ids()
+    }
+
+    fn cleanup_expired(&self) -> u64 {
+        self.inner.cleanup_expired()
+    }
+
+    fn vacuum(&self, full: bool) -> Result<(), MimirError> {
+        self.inner.vacuum(full)
+    }
+
+    fn flush(&self) -> Result<(), MimirError> {
+        self.inner.flush()
+    }
+
+    fn get_setting(&self, name: &str) -> Option<Vec<u8>> {
+        self.inner.get_setting(name)
+    }
+
+    fn set_setting(&self, name: &str, value: &[u8]) -> Result<(), MimirError> {
+        self.inner.set_setting(name, value)
+    }
+}
@@ -0,0 +1,184 @@
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use byteorder::{LittleEndian, BigEndian, WriteBytesExt};
+
+const PCAP_MAGIC: u32 = 0xa1b2c3d4;
+/// Linux "cooked" capture, unlike null encapsulation it keeps the direction of every packet
+const LINKTYPE_LINUX_SLL: u32 = 113;
+const SNAP_LEN: u32 = 65535;
+const ARPHRD_NONE: u16 = 0xfffe;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+const IP_PROTO_UDP: u8 = 17;
+const UDP_HEADER_SIZE: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Incoming,
+    Outgoing
+}
+
+/// Writes packets seen by the server to a pcap file, which can be opened in Wireshark or tcpdump
+pub struct PacketCapture {
+    writer: BufWriter<File>
+}
+
+impl PacketCapture {
+    /// Creates (or truncates) the file at `path` and writes the pcap global header
+    pub fn new(path: &str) -> Result<Self, io::Error> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_u32::<LittleEndian>(PCAP_MAGIC)?;
+        writer.write_u16::<LittleEndian>(2)?;
+        writer.write_u16::<LittleEndian>(4)?;
+        // Timezone offset and timestamp accuracy, always 0
+        writer.write_i32::<LittleEndian>(0)?;
+        writer.write_u32::<LittleEndian>(0)?;
+        writer.write_u32::<LittleEndian>(SNAP_LEN)?;
+        writer.write_u32::<LittleEndian>(LINKTYPE_LINUX_SLL)?;
+        Ok(PacketCapture { writer })
+    }
+
+    /// Writes one UDP datagram, `ts` is the time since UNIX epoch
+    pub fn record(&mut self, dir: Direction, src: SocketAddr, dst: SocketAddr, payload: &[u8], ts: Duration) -> Result<(), io::Error> {
+        let packet = build_packet(dir, src, dst, payload);
+        let length = packet.len() as u32;
+        let captured = length.min(SNAP_LEN);
+        self.writer.write_u32::<LittleEndian>(ts.as_secs() as u32)?;
+        self.writer.write_u32::<LittleEndian>(ts.subsec_micros())?;
+        self.writer.write_u32::<LittleEndian>(captured)?;
+        self.writer.write_u32::<LittleEndian>(length)?;
+        self.writer.write_all(&packet[..captured as usize])
+    }
+
+    pub fn flush(&mut self) -> Result<(), io::Error> {
+        self.writer.flush()
+    }
+}
+
+/// Builds SLL header + IP header + UDP header + payload
+fn build_packet(dir: Direction, src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let udp_length = (UDP_HEADER_SIZE + payload.len()) as u16;
+    let mut packet = Vec::with_capacity(16 + 40 + udp_length as usize);
+    // Writing to Vec never fails
+    let packet_type = match dir {
+        Direction::Incoming => 0,
+        Direction::Outgoing => 4
+    };
+    packet.write_u16::<BigEndian>(packet_type).unwrap();
+    packet.write_u16::<BigEndian>(ARPHRD_NONE).unwrap();
+    // No link-layer address
+    packet.write_u16::<BigEndian>(0).unwrap();
+    packet.write_all(&[0u8; 8]).unwrap();
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            packet.write_u16::<BigEndian>(ETHERTYPE_IPV4).unwrap();
+            let mut header = [0u8; 20];
+            header[0] = 0x45;
+            header[2..4].copy_from_slice(&(udp_length + 20).to_be_bytes());
+            header[8] = 64;
+            header[9] = IP_PROTO_UDP;
+            header[12..16].copy_from_slice(&src_ip.octets());
+            header[16..20].copy_from_slice(&dst_ip.octets());
+            let checksum = ipv4_checksum(&header);
+            header[10..12].copy_from_slice(&checksum.to_be_bytes());
+            packet.write_all(&header).unwrap();
+        }
+        (src_ip, dst_ip) => {
+            packet.write_u16::<BigEndian>(ETHERTYPE_IPV6).unwrap();
+            packet.write_u32::<BigEndian>(0x60000000).unwrap();
+            packet.write_u16::<BigEndian>(udp_length).unwrap();
+            packet.write_u8(IP_PROTO_UDP).unwrap();
+            packet.write_u8(64).unwrap();
+            packet.write_all(&to_ipv6(src_ip).octets()).unwrap();
+            packet.write_all(&to_ipv6(dst_ip).octets()).unwrap();
+        }
+    }
+    packet.write_u16::<BigEndian>(src.port()).unwrap();
+    packet.write_u16::<BigEndian>(dst.port()).unwrap();
+    packet.write_u16::<BigEndian>(udp_length).unwrap();
+    // Zero checksum, Wireshark doesn't verify UDP checksums by default
+    packet.write_u16::<BigEndian>(0).unwrap();
+    packet.write_all(payload).unwrap();
+    packet
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip
+    }
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], pair[1]]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    /// Record of a pcap file: timestamp, SLL packet type, ethertype, ports and UDP payload
+    type Record = (u32, u32, u16, u16, u16, u16, Vec<u8>);
+
+    fn read_records(bytes: &[u8]) -> Vec<Record> {
+        let u32_le = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap());
+        let u16_be = |at: usize| u16::from_be_bytes(bytes[at..at + 2].try_into().unwrap());
+        assert_eq!(u32_le(0), PCAP_MAGIC);
+        assert_eq!(u32_le(20), LINKTYPE_LINUX_SLL);
+        let mut records = Vec::new();
+        let mut at = 24;
+        while at < bytes.len() {
+            let (secs, micros, captured, length) = (u32_le(at), u32_le(at + 4), u32_le(at + 8) as usize, u32_le(at + 12) as usize);
+            assert_eq!(captured, length);
+            let packet = at + 16;
+            let ip_header_size = match u16_be(packet + 14) {
+                ETHERTYPE_IPV4 => 20,
+                _ => 40
+            };
+            let udp = packet + 16 + ip_header_size;
+            assert_eq!(u16_be(udp + 4) as usize, packet + length - udp);
+            records.push((secs, micros, u16_be(packet), u16_be(packet + 14), u16_be(udp), u16_be(udp + 2), bytes[udp + UDP_HEADER_SIZE..packet + length].to_vec()));
+            at = packet + length;
+        }
+        records
+    }
+
+    #[test]
+    fn records_are_read_back() {
+        let path = env::temp_dir().join(format!("mimir-capture-test-{}.pcap", std::process::id()));
+        let (client, server): (SocketAddr, SocketAddr) = ("[200::1]:6000".parse().unwrap(), "[200::2]:5050".parse().unwrap());
+        let (client_v4, server_v4): (SocketAddr, SocketAddr) = ("10.0.0.1:6000".parse().unwrap(), "10.0.0.2:5050".parse().unwrap());
+        let mut capture = PacketCapture::new(path.to_str().unwrap()).unwrap();
+        capture.record(Direction::Incoming, client, server, b"request", Duration::new(1_700_000_000, 5_000)).unwrap();
+        capture.record(Direction::Outgoing, server, client, b"answer", Duration::new(1_700_000_001, 0)).unwrap();
+        capture.record(Direction::Incoming, client_v4, server_v4, &[], Duration::new(1_700_000_002, 0)).unwrap();
+        capture.flush().unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(read_records(&bytes), vec![
+            (1_700_000_000, 5, 0, ETHERTYPE_IPV6, 6000, 5050, b"request".to_vec()),
+            (1_700_000_001, 0, 4, ETHERTYPE_IPV6, 5050, 6000, b"answer".to_vec()),
+            (1_700_000_002, 0, 0, ETHERTYPE_IPV4, 6000, 5050, Vec::new())
+        ]);
+    }
+
+    #[test]
+    fn ipv4_header_checksum_is_valid() {
+        let packet = build_packet(Direction::Incoming, "10.0.0.1:6000".parse().unwrap(), "10.0.0.2:5050".parse().unwrap(), b"data");
+        // Checksum of a header with its checksum is 0
+        assert_eq!(ipv4_checksum(&packet[16..36]), 0);
+    }
+}
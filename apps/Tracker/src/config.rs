@@ -0,0 +1,178 @@
+use std::fs;
+use serde::{Deserialize, Serialize};
+use crate::error::MimirError;
+
+/// Loaded from the working directory if `--config` is not given
+pub const DEFAULT_CONFIG_PATH: &str = "mimir-tracker.toml";
+
+/// Settings of the config file, every field is the command line flag with the same name.
+/// Missing fields keep the defaults of the flags, so an empty file is valid.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Used if no addresses are given on the command line
+    pub listen: Vec<String>,
+    pub storage: Option<String>,
+    pub db: Option<String>,
+    pub log_format: Option<String>,
+    pub response_ttl: Option<u64>,
+    pub cleanup_on_startup: bool,
+    pub cleanup_interval: Option<u64>,
+    pub vacuum_on_startup: bool,
+    /// `false` is the same as `--no-local-subnet-boost`
+    pub local_subnet_boost: Option<bool>,
+    pub sign_responses: bool,
+    pub pcap: Option<String>,
+    pub bind_device: Option<String>,
+    pub workers: Option<usize>,
+    pub bans: Vec<String>,
+    pub reject_ids: Vec<String>,
+    pub peers: Vec<String>,
+    pub max_public_priority: Option<u8>,
+    pub trusted_subnets: Vec<String>,
+    pub trusted_ips: Vec<String>,
+    pub reject_privileged_ports: bool,
+    pub lookup_notifications: bool,
+    pub reject_replayed_nonces: bool,
+    pub max_registrations_per_minute: Option<u32>,
+    pub max_addresses_per_id: Option<u64>,
+    pub max_time_skew: Option<u64>,
+    pub min_protocol_version: Option<u8>,
+    pub report_top_ips: Option<u64>,
+    pub metrics: bool,
+    pub metrics_port: Option<u16>,
+    pub watchdog_timeout: Option<u64>,
+    /// `false` is the same as `--no-watchdog`
+    pub watchdog: Option<bool>,
+    pub shutdown_timeout: Option<u64>
+}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Config, MimirError> {
+        let text = fs::read_to_string(path)?;
+        Config::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Config, MimirError> {
+        toml::from_str(text).map_err(|e| MimirError::InvalidData(e.to_string()))
+    }
+
+    /// Command line flags with the same meaning, except `listen`.
+    /// They are parsed before the real command line, so that flags given there win.
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        let mut value = |flag: &str, value: Option<String>| {
+            if let Some(value) = value {
+                args.push(flag.to_owned());
+                args.push(value);
+            }
+        };
+        value("--storage", self.storage.clone());
+        value("--db", self.db.clone());
+        value("--log-format", self.log_format.clone());
+        value("--response-ttl", self.response_ttl.map(|v| v.to_string()));
+        value("--cleanup-interval", self.cleanup_interval.map(|v| v.to_string()));
+        value("--pcap", self.pcap.clone());
+        value("--bind-device", self.bind_device.clone());
+        value("--workers", self.workers.map(|v| v.to_string()));
+        value("--max-public-priority", self.max_public_priority.map(|v| v.to_string()));
+        value("--max-registrations-per-minute", self.max_registrations_per_minute.map(|v| v.to_string()));
+        value("--max-addresses-per-id", self.max_addresses_per_id.map(|v| v.to_string()));
+        value("--max-time-skew", self.max_time_skew.map(|v| v.to_string()));
+        value("--min-protocol-version", self.min_protocol_version.map(|v| v.to_string()));
+        value("--report-top-ips", self.report_top_ips.map(|v| v.to_string()));
+        value("--metrics-port", self.metrics_port.map(|v| v.to_string()));
+        value("--watchdog-timeout", self.watchdog_timeout.map(|v| v.to_string()));
+        value("--shutdown-timeout", self.shutdown_timeout.map(|v| v.to_string()));
+        for (flag, values) in [("--ban", &self.bans), ("--reject-id", &self.reject_ids), ("--peer", &self.peers),
+                               ("--trusted-subnet", &self.trusted_subnets), ("--trusted-ip", &self.trusted_ips)] {
+            for v in values {
+                value(flag, Some(v.clone()));
+            }
+        }
+        for (flag, set) in [
+            ("--cleanup-on-startup", self.cleanup_on_startup),
+            ("--vacuum-on-startup", self.vacuum_on_startup),
+            ("--no-local-subnet-boost", self.local_subnet_boost == Some(false)),
+            ("--sign-responses", self.sign_responses),
+            ("--reject-privileged-ports", self.reject_privileged_ports),
+            ("--lookup-notifications", self.lookup_notifications),
+            ("--reject-replayed-nonces", self.reject_replayed_nonces),
+            ("--metrics", self.metrics),
+            ("--no-watchdog", self.watchdog == Some(false))
+        ] {
+            if set {
+                args.push(flag.to_owned());
+            }
+        }
+        args
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    const CONFIG: &str = r#"
+listen = ["[::1]:5050"]
+db = "config.sqlite"
+response_ttl = 300
+cleanup_on_startup = true
+local_subnet_boost = false
+peers = ["[::1]:6060", "[::1]:7070"]
+max_addresses_per_id = 8
+"#;
+
+    #[test]
+    fn empty_config_is_default() {
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+        assert!(Config::default().to_args().is_empty());
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        assert!(Config::parse("max_adresses_per_id = 8").is_err());
+        assert!(Config::parse("response_ttl = \"300\"").is_err());
+    }
+
+    #[test]
+    fn loads_config_file() {
+        let path = env::temp_dir().join(format!("mimir-config-test-{}.toml", std::process::id()));
+        fs::write(&path, CONFIG).unwrap();
+        let config = Config::load(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        let config = config.unwrap();
+        assert_eq!(config.listen, vec!["[::1]:5050".to_owned()]);
+        assert_eq!(config.db.as_deref(), Some("config.sqlite"));
+        assert_eq!(config.response_ttl, Some(300));
+        assert_eq!(config.local_subnet_boost, Some(false));
+        assert_eq!(config.peers.len(), 2);
+        assert!(!config.vacuum_on_startup);
+    }
+
+    #[test]
+    fn config_survives_round_trip() {
+        let mut config = Config::parse(CONFIG).unwrap();
+        config.watchdog = Some(true);
+        config.bans = vec!["200:1234::/48".to_owned()];
+        let text = toml::to_string(&config).unwrap();
+        assert_eq!(Config::parse(&text).unwrap(), config);
+        assert_eq!(Config::parse(&toml::to_string(&Config::default()).unwrap()).unwrap(), Config::default());
+    }
+
+    #[test]
+    fn command_line_overrides_config() {
+        let config = Config::parse(CONFIG).unwrap();
+        let command_line = ["--db", "cli.sqlite"].map(str::to_owned);
+        // Like main, that reads flags in this order and keeps the last value
+        let args: Vec<String> = config.to_args().into_iter().chain(command_line).collect();
+        let last_value = |flag: &str| args.iter().rposition(|arg| arg == flag).map(|index| args[index + 1].as_str());
+        assert_eq!(last_value("--db"), Some("cli.sqlite"));
+        assert_eq!(last_value("--response-ttl"), Some("300"));
+        assert_eq!(last_value("--max-addresses-per-id"), Some("8"));
+        assert_eq!(args.iter().filter(|arg| *arg == "--peer").count(), 2);
+        assert!(args.contains(&"--cleanup-on-startup".to_owned()));
+        assert!(args.contains(&"--no-local-subnet-boost".to_owned()));
+    }
+}
@@ -1,3 +1,5 @@
+-- Applies to new databases, existing ones switch to it on the next full VACUUM
+PRAGMA auto_vacuum = INCREMENTAL;
 CREATE TABLE IF NOT EXISTS clients (
     'id' BLOB NOT NULL,
     'ip' BLOB NOT NULL,
@@ -8,4 +10,32 @@ CREATE TABLE IF NOT EXISTS clients (
     'timestamp' INTEGER,
     'ttl' INTEGER
 );
-CREATE INDEX IF NOT EXISTS id_index ON clients (id);
\ No newline at end of file
+CREATE INDEX IF NOT EXISTS id_index ON clients (id);
+-- Covers counting active IDs of one IP, replaced the plain index on ip
+CREATE INDEX IF NOT EXISTS idx_clients_ip_id_timestamp_ttl ON clients (ip, id, timestamp, ttl);
+-- Touches and tombstones look addresses up by ID and IP, uniqueness is kept per (id, client) instead
+CREATE INDEX IF NOT EXISTS idx_clients_id_ip ON clients (id, ip);
+CREATE INDEX IF NOT EXISTS idx_clients_id_timestamp ON clients (id, timestamp);
+CREATE TABLE IF NOT EXISTS tombstones (
+    'id' BLOB NOT NULL,
+    'ip' BLOB NOT NULL,
+    'deleted_at' INTEGER NOT NULL,
+    'signature' BLOB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_tombstones_id_ip ON tombstones (id, ip);
+CREATE INDEX IF NOT EXISTS idx_tombstones_deleted_at ON tombstones (deleted_at);
+CREATE TABLE IF NOT EXISTS bans (
+    'ip_cidr' TEXT PRIMARY KEY,
+    'banned_at' INTEGER NOT NULL,
+    'expires_at' INTEGER NOT NULL,
+    'reason' TEXT
+);
+CREATE TABLE IF NOT EXISTS rejected_ids (
+    'id' BLOB PRIMARY KEY,
+    'rejected_at' INTEGER NOT NULL,
+    'reason' TEXT
+);
+CREATE TABLE IF NOT EXISTS settings (
+    'name' TEXT PRIMARY KEY,
+    'value' BLOB NOT NULL
+);
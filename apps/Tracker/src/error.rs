@@ -0,0 +1,40 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+
+#[derive(Debug)]
+pub enum MimirError {
+    Io(io::Error),
+    Db(sqlite::Error),
+    /// Input that can't be used, with the reason
+    InvalidData(String),
+    /// Storage backend with this name is unknown or not compiled in
+    UnsupportedBackend(String),
+    /// Packet ended before the field at `position`
+    MalformedPacket { position: usize }
+}
+
+impl Display for MimirError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MimirError::Io(e) => write!(f, "I/O error: {}", e),
+            MimirError::Db(e) => write!(f, "DB error: {}", e),
+            MimirError::InvalidData(reason) => write!(f, "Invalid data: {}", reason),
+            MimirError::UnsupportedBackend(name) => write!(f, "Unsupported storage backend '{}', expected sqlite or memory", name),
+            MimirError::MalformedPacket { position } => write!(f, "Malformed packet, too short at byte {}", position)
+        }
+    }
+}
+
+impl std::error::Error for MimirError {}
+
+impl From<io::Error> for MimirError {
+    fn from(e: io::Error) -> Self {
+        MimirError::Io(e)
+    }
+}
+
+impl From<sqlite::Error> for MimirError {
+    fn from(e: sqlite::Error) -> Self {
+        MimirError::Db(e)
+    }
+}
@@ -0,0 +1,269 @@
+use std::cmp::Reverse;
+use std::io;
+use std::io::{Cursor, Read, Write};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::num::NonZeroUsize;
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use lru::LruCache;
+use tracing::warn;
+use crate::error::MimirError;
+use crate::functions::{check_addr_signature, deduplicate};
+use crate::protocol::Command;
+use crate::server::RESPONSE_BUFFER_SIZE;
+use crate::storage::{get_utc_time, Addr, Storage};
+use crate::version::PROTOCOL_VERSION;
+
+/// Size of one address in command-1 answers before `latency_hint_ms`, `flags` and `signed_at` were added
+const ADDR_SIZE_V1: usize = 95;
+pub const DEFAULT_PEER_TIMEOUT: Duration = Duration::from_millis(500);
+pub const DEFAULT_CACHE_SECS: u64 = 60;
+/// Answers start with `nonce` u32 and command u8
+const ANSWER_HEADER_SIZE: u64 = 5;
+/// Name of the setting with saved peers, one `address priority` per line
+const PEERS_SETTING: &str = "federation_peers";
+/// Number of IDs whose forwarded results are kept, lookups of random IDs can't grow it further
+const CACHE_CAPACITY: usize = 4096;
+/// Lookups waiting for the forwarding thread, more are dropped until it catches up
+const FORWARD_QUEUE_SIZE: usize = 256;
+
+struct CachedLookup {
+    addrs: Vec<Addr>,
+    fetched_at: Instant
+}
+
+/// Asks peer trackers for addresses of IDs that are not registered here
+pub struct FederationManager {
+    /// Peer trackers with their priorities, higher priority peers are asked first
+    peers: Vec<(SocketAddr, u8)>,
+    timeout: Duration,
+    cache_time: Duration,
+    cache: Mutex<LruCache<Vec<u8>, CachedLookup>>,
+    /// Set by `start_forwarding`, lookups are forwarded in the caller thread without it
+    queue: Mutex<Option<SyncSender<[u8; 32]>>>,
+    nonce: AtomicU32
+}
+
+impl FederationManager {
+    pub fn new(timeout: Duration, cache_secs: u64) -> Self {
+        FederationManager {
+            peers: Vec::new(),
+            timeout,
+            cache_time: Duration::from_secs(cache_secs),
+            cache: Mutex::new(LruCache::new(NonZeroUsize::new(CACHE_CAPACITY).unwrap())),
+            queue: Mutex::new(None),
+            nonce: AtomicU32::new(get_utc_time() as u32)
+        }
+    }
+
+    pub fn add_peer(&mut self, addr: SocketAddr, priority: u8) {
+        self.peers.push((addr, priority));
+        self.peers.sort_by_key(|(_, priority)| Reverse(*priority));
+    }
+
+    pub fn has_peers(&self) -> bool {
+        !self.peers.is_empty()
+    }
+
+    /// Checks if this IP belongs to one of peers, their queries are never forwarded to avoid loops
+    pub fn is_peer(&self, ip: &IpAddr) -> bool {
+        self.peers.iter().any(|(addr, _)| &addr.ip() == ip)
+    }
+
+    pub fn peers(&self) -> &[(SocketAddr, u8)] {
+        &self.peers
+    }
+
+    /// Pings every peer and removes the ones that don't answer, returns the removed ones
+    pub fn remove_unreachable_peers(&mut self) -> Vec<SocketAddr> {
+        let (reachable, unreachable): (Vec<_>, Vec<_>) = self.peers.iter().partition(|(peer, _)| match self.ping_peer(peer) {
+            Ok(_) => true,
+            Err(e) => {
+                warn!("Removing unreachable peer tracker {}: {}", peer, e);
+                false
+            }
+        });
+        self.peers = reachable;
+        unreachable.into_iter().map(|(peer, _)| peer).collect()
+    }
+
+    /// Adds peers saved with `save_peers`, wrong lines are skipped
+    pub fn load_peers(&mut self, storage: &dyn Storage) {
+        let Some(saved) = storage.get_setting(PEERS_SETTING) else { return };
+        for line in String::from_utf8_lossy(&saved).lines() {
+            match line.split_once(' ').and_then(|(addr, priority)| Some((addr.parse().ok()?, priority.parse().ok()?))) {
+                Some((addr, priority)) => self.add_peer(addr, priority),
+                None => warn!("Ignoring saved peer tracker {}", line)
+            }
+        }
+    }
+
+    /// Saves the peers to storage, so that they are used after restart
+    pub fn save_peers(&self, storage: &dyn Storage) -> Result<(), MimirError> {
+        let saved: String = self.peers.iter().map(|(addr, priority)| format!("{} {}\n", addr, priority)).collect();
+        storage.set_setting(PEERS_SETTING, saved.as_bytes())
+    }
+
+    /// Starts the thread that forwards lookups queued by `lookup`, their results are cached for the next lookups
+    pub fn start_forwarding(self: &Arc<Self>) -> JoinHandle<()> {
+        let (sender, receiver) = sync_channel::<[u8; 32]>(FORWARD_QUEUE_SIZE);
+        *self.queue.lock().unwrap() = Some(sender);
+        let federation = Arc::clone(self);
+        thread::spawn(move || {
+            for id in receiver {
+                federation.forward_lookup(&id);
+            }
+        })
+    }
+
+    /// Gets cached results of peers, or queues the lookup for the forwarding thread and returns nothing for now.
+    /// Empty results are cached too, so lookups of unknown IDs don't reach peers more than once per cache time.
+    /// Without `start_forwarding` it is `forward_lookup`.
+    pub fn lookup(&self, id: &[u8; 32]) -> Vec<Addr> {
+        if let Some(cached) = self.cached_lookup(id) {
+            return cached;
+        }
+        match self.queue.lock().unwrap().as_ref() {
+            Some(queue) => {
+                if queue.try_send(*id).is_err() {
+                    warn!("Too many lookups to forward, dropped one");
+                }
+                Vec::new()
+            }
+            None => self.forward_lookup(id)
+        }
+    }
+
+    fn cached_lookup(&self, id: &[u8; 32]) -> Option<Vec<Addr>> {
+        let mut cache = self.cache.lock().unwrap();
+        let cached = cache.get(id.as_slice())?;
+        (cached.fetched_at.elapsed() < self.cache_time).then(|| cached.addrs.clone())
+    }
+
+    /// Queries all peers in priority order and merges their results.
+    /// Every peer that doesn't answer blocks the caller for the whole timeout.
+    pub fn forward_lookup(&self, id: &[u8; 32]) -> Vec<Addr> {
+        if let Some(cached) = self.cached_lookup(id) {
+            return cached;
+        }
+        let mut result = Vec::new();
+        for (peer, _) in self.peers.iter() {
+            match self.lookup_peer(peer, id) {
+                Ok(addrs) => result.extend(addrs),
+                Err(e) => warn!("Error querying peer tracker {}: {}", peer, e)
+            }
+        }
+        let result = deduplicate(result);
+        let cached = CachedLookup { addrs: result.clone(), fetched_at: Instant::now() };
+        self.cache.lock().unwrap().put(id.to_vec(), cached);
+        result
+    }
+
+    /// Asks the peer for its max protocol version with command 5
+    fn ping_peer(&self, peer: &SocketAddr) -> Result<u8, io::Error> {
+        let mut buf = [0u8; RESPONSE_BUFFER_SIZE];
+        let length = self.request_peer(peer, Command::Ping, &[0u8; 32], &mut buf)?;
+        let mut c = Cursor::new(&buf[..length]);
+        c.set_position(ANSWER_HEADER_SIZE);
+        c.read_u8()
+    }
+
+    fn lookup_peer(&self, peer: &SocketAddr, id: &[u8; 32]) -> Result<Vec<Addr>, io::Error> {
+        let mut buf = [0u8; RESPONSE_BUFFER_SIZE];
+        let length = self.request_peer(peer, Command::Lookup, id, &mut buf)?;
+        let mut c = Cursor::new(&buf[..length]);
+        c.set_position(ANSWER_HEADER_SIZE);
+        let count = c.read_u8()?;
+        // Peers running older versions answer with v1 layout
+        let is_v2 = count > 0 && length - c.position() as usize != count as usize * ADDR_SIZE_V1;
+        let mut result = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let mut ip = vec![0u8; 16];
+            c.read_exact(&mut ip)?;
+            let mut signature = [0u8; 64];
+            c.read_exact(&mut signature)?;
+            let port = c.read_u16::<BigEndian>()?;
+            let priority = c.read_u8()?;
+            let client = c.read_u32::<BigEndian>()?;
+            let ttl = c.read_u64::<BigEndian>()?;
+            let latency_hint_ms = if is_v2 { c.read_u16::<BigEndian>()? } else { 0 };
+            let flags = if is_v2 { c.read_u8()? } else { 0 };
+            let signed_at = if is_v2 { c.read_u32::<BigEndian>()? } else { 0 };
+            let addr = Addr { ip, signature: signature.to_vec(), port, priority, client, ttl, latency_hint_ms, flags, signed_at };
+            // Peers are trusted to route queries, not to vouch for addresses
+            if !check_addr_signature(id, &addr) {
+                warn!("Wrong signature in answer from peer tracker {}", peer);
+                continue;
+            }
+            result.push(addr);
+        }
+        Ok(result)
+    }
+
+    /// Sends request without optional fields and waits for its answer, returns the length of the answer in `buf`
+    fn request_peer(&self, peer: &SocketAddr, command: Command, id: &[u8; 32], buf: &mut [u8]) -> Result<usize, io::Error> {
+        let local = match peer {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0"
+        };
+        let socket = UdpSocket::bind(local)?;
+        let nonce = self.nonce.fetch_add(1, Ordering::Relaxed);
+        let mut request = Vec::with_capacity(42);
+        request.write_u8(PROTOCOL_VERSION)?;
+        request.write_u32::<BigEndian>(nonce)?;
+        request.write_u32::<BigEndian>(get_utc_time() as u32)?;
+        request.write_u8(command.byte())?;
+        request.write_all(id)?;
+        socket.send_to(&request, peer)?;
+
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::Error::from(io::ErrorKind::TimedOut));
+            }
+            socket.set_read_timeout(Some(remaining))?;
+            let (length, src) = socket.recv_from(buf)?;
+            if &src != peer {
+                continue;
+            }
+            let mut c = Cursor::new(&buf[..length]);
+            if c.read_u32::<BigEndian>()? != nonce || c.read_u8()? != command.byte() {
+                continue;
+            }
+            return Ok(length);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookups_are_forwarded_in_background_and_cached() {
+        // Never answers, every forwarded lookup takes the whole timeout
+        let silent_peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let timeout = Duration::from_millis(100);
+        let mut federation = FederationManager::new(timeout, DEFAULT_CACHE_SECS);
+        federation.add_peer(silent_peer.local_addr().unwrap(), 0);
+        let federation = Arc::new(federation);
+        federation.start_forwarding();
+
+        let id = [1u8; 32];
+        let start = Instant::now();
+        assert!(federation.lookup(&id).is_empty());
+        assert!(start.elapsed() < timeout);
+
+        let deadline = Instant::now() + timeout * 10;
+        while federation.cached_lookup(&id).is_none() {
+            assert!(Instant::now() < deadline, "lookup was not cached");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(federation.cached_lookup(&id), Some(Vec::new()));
+    }
+}
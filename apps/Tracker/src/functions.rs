@@ -1,8 +1,255 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::hash::Hash;
 use ed25519_dalek::{PublicKey, Signature, Verifier};
+use crate::protocol::Command;
+use crate::storage::{Addr, ClientId, PortNum, Priority, ADDR_FLAG_FULL_SIGNATURE};
 
-/// Checks if given signature is valid for given public key and data
-pub fn check_signature(public_key: &[u8], signature: &[u8], data: &[u8]) -> bool {
-    let public_key = PublicKey::from_bytes(&public_key).unwrap();
-    let signature = Signature::from_bytes(&signature).unwrap();
-    public_key.verify(&data, &signature).is_ok()
-}
\ No newline at end of file
+/// Checks if given signature is valid for given public key and data.
+/// Keys that are not valid curve points fail the check.
+pub fn check_signature(public_key: &[u8; 32], signature: &[u8; 64], data: &[u8]) -> bool {
+    let public_key = match PublicKey::from_bytes(public_key) {
+        Ok(public_key) => public_key,
+        Err(_) => return false
+    };
+    let signature = match Signature::from_bytes(signature) {
+        Ok(signature) => signature,
+        Err(_) => return false
+    };
+    public_key.verify(data, &signature).is_ok()
+}
+
+/// Checks signature like `check_signature`, slices of wrong length fail the check
+#[deprecated(note = "use check_signature with fixed-size arrays")]
+pub fn check_signature_bytes(public_key: &[u8], signature: &[u8], data: &[u8]) -> bool {
+    match (public_key.try_into(), signature.try_into()) {
+        (Ok(public_key), Ok(signature)) => check_signature(public_key, signature, data),
+        _ => false
+    }
+}
+
+/// Checks signature of `ip` made by v2 clients together with request timestamp `signed_at`, or of `ip` alone if it is 0
+pub fn check_ip_signature(public_key: &[u8; 32], signature: &[u8; 64], ip: &[u8], signed_at: u32) -> bool {
+    if signed_at == 0 {
+        return check_signature(public_key, signature, ip);
+    }
+    let mut data = Vec::with_capacity(4 + ip.len());
+    data.extend_from_slice(&signed_at.to_be_bytes());
+    data.extend_from_slice(ip);
+    check_signature(public_key, signature, &data)
+}
+
+/// Data signed in registrations since `REGISTRATION_SIGNATURE_VERSION`:
+/// `signed_at || id || ip || port || priority || client`, numbers in big endian
+pub fn registration_signed_data(id: &[u8; 32], ip: &[u8], port: PortNum, priority: Priority, client: ClientId, signed_at: u32) -> Vec<u8> {
+    let mut data = Vec::with_capacity(4 + 32 + ip.len() + 2 + 1 + 4);
+    data.extend_from_slice(&signed_at.to_be_bytes());
+    data.extend_from_slice(id);
+    data.extend_from_slice(ip);
+    data.extend_from_slice(&port.to_be_bytes());
+    data.push(priority);
+    data.extend_from_slice(&client.to_be_bytes());
+    data
+}
+
+/// Checks signature of all fields of a registration by the key of `id`, unlike `check_ip_signature`
+/// port, priority and client can't be changed by anyone who relays the packet
+pub fn check_registration_signature(id: &[u8; 32], ip: &[u8], port: PortNum, priority: Priority, client: ClientId, signed_at: u32, signature: &[u8; 64]) -> bool {
+    check_signature(id, signature, &registration_signed_data(id, ip, port, priority, client, signed_at))
+}
+
+/// Data signed in deregistrations before `REGISTRATION_SIGNATURE_VERSION`: `2 || signed_at || id || ip || client`.
+/// The command byte in front keeps signatures of registrations, that lookups give out to anyone, from deleting addresses.
+pub fn deregistration_signed_data(id: &[u8; 32], ip: &[u8], client: ClientId, signed_at: u32) -> Vec<u8> {
+    let mut data = Vec::with_capacity(1 + 4 + 32 + ip.len() + 4);
+    data.push(Command::Deregister.byte());
+    data.extend_from_slice(&signed_at.to_be_bytes());
+    data.extend_from_slice(id);
+    data.extend_from_slice(ip);
+    data.extend_from_slice(&client.to_be_bytes());
+    data
+}
+
+/// Data signed in deregistrations since `REGISTRATION_SIGNATURE_VERSION`, all fields of the request:
+/// `2 || signed_at || id || ip || port || priority || client || flags`, numbers in big endian
+pub fn full_deregistration_signed_data(id: &[u8; 32], ip: &[u8], port: PortNum, priority: Priority, client: ClientId, flags: u8, signed_at: u32) -> Vec<u8> {
+    let mut data = Vec::with_capacity(1 + 4 + 32 + ip.len() + 2 + 1 + 4 + 1);
+    data.push(Command::Deregister.byte());
+    data.extend_from_slice(&signed_at.to_be_bytes());
+    data.extend_from_slice(id);
+    data.extend_from_slice(ip);
+    data.extend_from_slice(&port.to_be_bytes());
+    data.push(priority);
+    data.extend_from_slice(&client.to_be_bytes());
+    data.push(flags);
+    data
+}
+
+/// Checks signature of a saved address of `id`, `ADDR_FLAG_FULL_SIGNATURE` in its flags tells what was signed
+pub fn check_addr_signature(id: &[u8; 32], addr: &Addr) -> bool {
+    let Ok(signature) = addr.signature.as_slice().try_into() else { return false };
+    match addr.flags & ADDR_FLAG_FULL_SIGNATURE != 0 {
+        true => check_registration_signature(id, &addr.ip, addr.port, addr.priority, addr.client, addr.signed_at, signature),
+        false => check_ip_signature(id, signature, &addr.ip, addr.signed_at)
+    }
+}
+
+/// Convert bytes array to HEX format
+pub fn to_hex(buf: &[u8]) -> String {
+    let mut result = String::new();
+    for x in buf.iter() {
+        result.push_str(&format!("{:01$X}", x, 2));
+    }
+    result
+}
+
+/// Parses HEX string, returns None if it has odd length or other characters
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| hex.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect()
+}
+
+/// Parses HEX string of exactly `N` bytes
+pub fn from_hex_array<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    from_hex(hex)?.try_into().ok()
+}
+
+/// Formats UTC time in seconds as ISO-8601 like `2024-05-01T12:30:00Z`
+pub fn format_utc_time(secs: u64) -> String {
+    // Civil date from days since the epoch, counted in 400-year eras that start on March 1
+    let days = secs / 86400 + 719468;
+    let era = days / 146097;
+    let day_of_era = days % 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 { month_from_march + 3 } else { month_from_march - 9 };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+    let time = secs % 86400;
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, time / 3600, time % 3600 / 60, time % 60)
+}
+
+/// Removes addresses with the same `ip`, `port` and `client`, keeping the one with higher priority
+pub fn deduplicate(addrs: Vec<Addr>) -> Vec<Addr> {
+    deduplicate_by(addrs, |addr| (addr.ip.clone(), addr.port, addr.client))
+}
+
+/// Keeps one address per `key`, the one with higher priority wins, then the one signed later.
+/// It takes the place of the first one, so the order of the rest is kept.
+pub fn deduplicate_by<K: Hash + Eq>(addrs: Vec<Addr>, key: impl Fn(&Addr) -> K) -> Vec<Addr> {
+    let mut result: Vec<Addr> = Vec::with_capacity(addrs.len());
+    let mut positions: HashMap<K, usize> = HashMap::new();
+    for addr in addrs {
+        match positions.entry(key(&addr)) {
+            Entry::Occupied(entry) => {
+                let kept = &result[*entry.get()];
+                if (addr.priority, addr.signed_at) > (kept.priority, kept.signed_at) {
+                    result[*entry.get()] = addr;
+                }
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(result.len());
+                result.push(addr);
+            }
+        }
+    }
+    result
+}
+
+/// Keeps one address per `ip` and `port`, like ones saved again under another `client` after a reinstall
+pub fn dedup_addrs(addrs: Vec<Addr>) -> Vec<Addr> {
+    deduplicate_by(addrs, |addr| (addr.ip.clone(), addr.port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::{generate_keypairs, sign_deregistration, sign_full_deregistration, sign_ip, sign_registration};
+
+    const IP: [u8; 16] = [2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+    const SIGNED_AT: u32 = 1_700_000_000;
+
+    fn addr(ip: u8, port: PortNum, priority: Priority, client: ClientId, signed_at: u32) -> Addr {
+        Addr { ip: vec![ip; 16], signature: vec![0; 64], port, priority, client, ttl: 600, latency_hint_ms: 0, flags: 0, signed_at }
+    }
+
+    #[test]
+    fn ip_signatures_of_all_versions_pass() {
+        let (key, id) = &generate_keypairs(1)[0];
+        // Versions 0 and 1 sign only ip, version 2 signs the request timestamp too
+        assert!(check_ip_signature(id, &sign_ip(key, IP, 0), &IP, 0));
+        assert!(check_ip_signature(id, &sign_ip(key, IP, SIGNED_AT), &IP, SIGNED_AT));
+        assert!(!check_ip_signature(id, &sign_ip(key, IP, SIGNED_AT), &IP, SIGNED_AT + 1));
+        assert!(!check_ip_signature(id, &sign_ip(key, IP, 0), &[3; 16], 0));
+    }
+
+    #[test]
+    fn registration_signature_covers_all_fields() {
+        let (key, id) = &generate_keypairs(1)[0];
+        let signature = sign_registration(key, IP, 5050, 1, 7, SIGNED_AT);
+        assert!(check_registration_signature(id, &IP, 5050, 1, 7, SIGNED_AT, &signature));
+        assert!(!check_registration_signature(id, &[3; 16], 5050, 1, 7, SIGNED_AT, &signature), "changed ip");
+        assert!(!check_registration_signature(id, &IP, 5051, 1, 7, SIGNED_AT, &signature), "changed port");
+        assert!(!check_registration_signature(id, &IP, 5050, 2, 7, SIGNED_AT, &signature), "changed priority");
+        assert!(!check_registration_signature(id, &IP, 5050, 1, 8, SIGNED_AT, &signature), "changed client");
+        assert!(!check_registration_signature(id, &IP, 5050, 1, 7, SIGNED_AT + 1, &signature), "changed signed_at");
+        let (_, other_id) = &generate_keypairs(2)[1];
+        assert!(!check_registration_signature(other_id, &IP, 5050, 1, 7, SIGNED_AT, &signature), "other ID");
+    }
+
+    #[test]
+    fn v2_signature_fails_as_registration_signature() {
+        let (key, id) = &generate_keypairs(1)[0];
+        let signature = sign_ip(key, IP, SIGNED_AT);
+        assert!(!check_registration_signature(id, &IP, 5050, 1, 7, SIGNED_AT, &signature));
+    }
+
+    #[test]
+    fn addr_signature_follows_flags() {
+        let (key, id) = &generate_keypairs(1)[0];
+        let mut addr = Addr { ip: IP.to_vec(), signature: sign_ip(key, IP, SIGNED_AT).to_vec(), port: 5050, priority: 1, client: 7, ttl: 600, latency_hint_ms: 0, flags: 0, signed_at: SIGNED_AT };
+        assert!(check_addr_signature(id, &addr));
+        addr.flags = ADDR_FLAG_FULL_SIGNATURE;
+        assert!(!check_addr_signature(id, &addr));
+        addr.signature = sign_registration(key, IP, 5050, 1, 7, SIGNED_AT).to_vec();
+        assert!(check_addr_signature(id, &addr));
+        addr.signature.truncate(32);
+        assert!(!check_addr_signature(id, &addr));
+    }
+
+    #[test]
+    fn deregistration_signatures_differ_from_registration_ones() {
+        let (key, id) = &generate_keypairs(1)[0];
+        let registration = sign_ip(key, IP, SIGNED_AT);
+        let full_registration = sign_registration(key, IP, 5050, 1, 7, SIGNED_AT);
+        for signature in [registration, full_registration] {
+            assert!(!check_signature(id, &signature, &deregistration_signed_data(id, &IP, 7, SIGNED_AT)));
+            assert!(!check_signature(id, &signature, &full_deregistration_signed_data(id, &IP, 5050, 1, 7, 0, SIGNED_AT)));
+        }
+        let signature = sign_deregistration(key, IP, 7, SIGNED_AT);
+        assert!(check_signature(id, &signature, &deregistration_signed_data(id, &IP, 7, SIGNED_AT)));
+        assert!(!check_signature(id, &signature, &deregistration_signed_data(id, &IP, 8, SIGNED_AT)));
+        let signature = sign_full_deregistration(key, IP, 5050, 1, 7, 0, SIGNED_AT);
+        assert!(check_signature(id, &signature, &full_deregistration_signed_data(id, &IP, 5050, 1, 7, 0, SIGNED_AT)));
+        assert!(!check_signature(id, &signature, &full_deregistration_signed_data(id, &IP, 5050, 1, 7, 1, SIGNED_AT)), "changed flags");
+    }
+
+    #[test]
+    fn deduplicate_keeps_higher_priority_in_place() {
+        let addrs = vec![addr(1, 5000, 1, 7, 0), addr(2, 5000, 1, 7, 0), addr(1, 5000, 3, 7, 0), addr(1, 5000, 2, 8, 0)];
+        let result = deduplicate(addrs);
+        assert_eq!(result, vec![addr(1, 5000, 3, 7, 0), addr(2, 5000, 1, 7, 0), addr(1, 5000, 2, 8, 0)]);
+    }
+
+    #[test]
+    fn dedup_addrs_ignores_client_and_prefers_later_signature() {
+        let addrs = vec![addr(1, 5000, 1, 7, 100), addr(1, 5000, 1, 8, 200), addr(1, 6000, 1, 7, 0)];
+        assert_eq!(dedup_addrs(addrs), vec![addr(1, 5000, 1, 8, 200), addr(1, 6000, 1, 7, 0)]);
+    }
+}
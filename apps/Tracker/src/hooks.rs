@@ -0,0 +1,19 @@
+use std::net::Ipv6Addr;
+use crate::error::MimirError;
+use crate::storage::{ClientId, PortNum, Priority};
+
+/// Custom rules for registrations (command 0), like invite-only trackers or rejecting private ranges.
+/// Hooks are called after the signature is checked and before the address is saved.
+pub trait RegistrationHook: Send + Sync {
+    /// Returns an error to reject the registration, the client gets `ErrorCode::RegistrationRejected`
+    fn check(&self, id: &[u8; 32], ip: &Ipv6Addr, port: PortNum, priority: Priority, client: ClientId) -> Result<(), MimirError>;
+}
+
+/// Accepts every registration, as a server without hooks does
+pub struct NoOpHook;
+
+impl RegistrationHook for NoOpHook {
+    fn check(&self, _id: &[u8; 32], _ip: &Ipv6Addr, _port: PortNum, _priority: Priority, _client: ClientId) -> Result<(), MimirError> {
+        Ok(())
+    }
+}
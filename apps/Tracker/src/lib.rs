@@ -0,0 +1,28 @@
+pub mod error;
+pub mod packet;
+pub mod protocol;
+pub mod server;
+pub mod storage;
+pub mod backend;
+mod queries;
+pub mod functions;
+pub mod cache;
+pub mod config;
+pub mod federation;
+pub mod capture;
+pub mod ratelimit;
+pub mod ban;
+pub mod reject;
+pub mod hooks;
+pub mod logging;
+pub mod metrics;
+pub mod nonce;
+pub mod notify;
+pub mod shutdown;
+pub mod version;
+pub mod watchdog;
+pub mod serde_hex;
+#[cfg(feature = "statsd")]
+pub mod statsd;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_helpers;
@@ -0,0 +1,56 @@
+use std::env;
+use std::str::FromStr;
+use tracing::level_filters::LevelFilter;
+use tracing::warn;
+use tracing_subscriber::EnvFilter;
+
+/// Overrides the default log format, the `--log-format` argument overrides this variable
+pub const LOG_FORMAT_ENV: &str = "MIMIR_LOG_FORMAT";
+/// Filter directives of logged events, like `debug` or `tracker=debug,warn` for debug of the tracker only
+pub const LOG_LEVEL_ENV: &str = "RUST_LOG";
+/// Used if `RUST_LOG` is not set, requests are logged only at `debug`
+pub const DEFAULT_LOG_LEVEL: LevelFilter = LevelFilter::INFO;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format '{}', expected json or text", s))
+        }
+    }
+}
+
+impl LogFormat {
+    /// Gets format from `MIMIR_LOG_FORMAT`, or `Text` if it is not set
+    pub fn from_env() -> Result<Self, String> {
+        match env::var(LOG_FORMAT_ENV) {
+            Ok(value) => value.parse(),
+            Err(_) => Ok(LogFormat::Text)
+        }
+    }
+}
+
+/// Installs global tracing subscriber with the filter of `RUST_LOG`, must be called once before the server starts
+pub fn init_logging(format: LogFormat) {
+    let (filter, error) = match EnvFilter::try_from_default_env() {
+        Ok(filter) => (filter, None),
+        Err(e) => (EnvFilter::default().add_directive(DEFAULT_LOG_LEVEL.into()), env::var(LOG_LEVEL_ENV).ok().map(|value| (value, e)))
+    };
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    match format {
+        LogFormat::Text => builder.pretty().init(),
+        LogFormat::Json => builder.json().init()
+    }
+    if let Some((value, e)) = error {
+        warn!("Wrong {} value '{}', logging at {}: {}", LOG_LEVEL_ENV, value, DEFAULT_LOG_LEVEL, e);
+    }
+}
@@ -1,22 +1,548 @@
 use std::env;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV6};
+use std::path::Path;
 use std::process::exit;
-use crate::server::Server;
-
-mod server;
-mod storage;
-mod functions;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Duration;
+use tracker::backend::StorageBackend;
+use tracker::ban::IpNet;
+use tracker::capture::PacketCapture;
+use tracker::config::{Config, DEFAULT_CONFIG_PATH};
+use tracker::error::MimirError;
+use tracker::federation::{FederationManager, DEFAULT_CACHE_SECS, DEFAULT_PEER_TIMEOUT};
+use tracker::functions::from_hex_array;
+use tracker::ratelimit::RateLimiter;
+use tracker::logging::{init_logging, LogFormat};
+use tracker::metrics::{ConnectionMetrics, MetricsServer, TrackerMetrics};
+use tracker::server::{DEFAULT_DB_PATH, Server};
+#[cfg(feature = "statsd")]
+use tracker::statsd::{StatsdEmitter, DEFAULT_STATSD_ADDR, DEFAULT_STATSD_INTERVAL, STATSD_ADDR_ENV};
+use tracker::shutdown::{install_signal_handlers, join_with_timeout, wait_for_shutdown, DEFAULT_SHUTDOWN_TIMEOUT};
+use tracker::storage::{SqliteStorage, Storage};
+use tracker::version::{Version, PROTOCOL_VERSION};
+use tracing::{error, info, warn};
 
 fn main() {
-    println!("Mimir tracker {}", env!("CARGO_PKG_VERSION"));
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        println!("Usage: ./tracker [IPv6]:port");
-        exit(0);
+    let mut listen_addresses = Vec::new();
+    let mut dry_run = false;
+    let mut db_path = None;
+    let mut storage_backend = None;
+    let mut log_format = None;
+    let mut response_ttl = None;
+    let mut cleanup_on_startup = false;
+    let mut cleanup_interval = None;
+    let mut vacuum_on_startup = false;
+    let mut local_subnet_boost = true;
+    let mut sign_responses = false;
+    let mut pcap_path = None;
+    let mut bind_device = None;
+    let mut bans = Vec::new();
+    let mut rejected_ids = Vec::new();
+    let mut peers = Vec::new();
+    let mut max_public_priority = None;
+    let mut trusted_subnets = Vec::new();
+    let mut trusted_ips = Vec::new();
+    let mut reject_privileged_ports = false;
+    let mut lookup_notifications = false;
+    let mut reject_replayed_nonces = false;
+    let mut metrics = false;
+    let mut metrics_port = None;
+    let mut workers = None;
+    let mut min_protocol_version = None;
+    let mut max_registrations = None;
+    let mut max_addresses_per_id = None;
+    let mut max_time_skew = None;
+    let mut report_top_ips = None;
+    let mut watchdog_timeout = None;
+    let mut watchdog = true;
+    let mut shutdown_timeout = None;
+    let mut import_path = None;
+    let mut export_csv_path = None;
+    let mut skip_sig_check = false;
+    let command_line: Vec<String> = env::args().skip(1).collect();
+    let (config, config_path) = load_config(&command_line);
+    // Flags of the config file go first, the same flags on the command line replace them
+    let mut args = config.to_args().into_iter().chain(command_line);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dry-run" => dry_run = true,
+            "--db" => db_path = args.next(),
+            "--storage" => storage_backend = args.next(),
+            "--log-format" => log_format = args.next(),
+            "--response-ttl" => response_ttl = args.next(),
+            "--cleanup-on-startup" => cleanup_on_startup = true,
+            "--cleanup-interval" => cleanup_interval = args.next(),
+            "--vacuum-on-startup" => vacuum_on_startup = true,
+            "--no-local-subnet-boost" => local_subnet_boost = false,
+            "--sign-responses" => sign_responses = true,
+            "--pcap" => pcap_path = args.next(),
+            "--bind-device" => bind_device = args.next(),
+            "--ban" => bans.extend(args.next()),
+            "--reject-id" => rejected_ids.extend(args.next()),
+            "--peer" => peers.extend(args.next()),
+            "--max-public-priority" => max_public_priority = args.next(),
+            "--trusted-subnet" => trusted_subnets.extend(args.next()),
+            "--trusted-ip" => trusted_ips.extend(args.next()),
+            "--reject-privileged-ports" => reject_privileged_ports = true,
+            "--lookup-notifications" => lookup_notifications = true,
+            "--reject-replayed-nonces" => reject_replayed_nonces = true,
+            "--metrics" => metrics = true,
+            "--metrics-port" => metrics_port = args.next(),
+            "--workers" => workers = args.next(),
+            "--min-protocol-version" => min_protocol_version = args.next(),
+            "--max-registrations-per-minute" => max_registrations = args.next(),
+            "--max-addresses-per-id" => max_addresses_per_id = args.next(),
+            "--max-time-skew" => max_time_skew = args.next(),
+            "--report-top-ips" => report_top_ips = args.next(),
+            "--watchdog-timeout" => watchdog_timeout = args.next(),
+            "--no-watchdog" => watchdog = false,
+            "--shutdown-timeout" => shutdown_timeout = args.next(),
+            "--import" => import_path = args.next(),
+            "--export-csv" => export_csv_path = args.next(),
+            "--skip-sig-check" => skip_sig_check = true,
+            // Loaded before other flags
+            "--config" => {
+                args.next();
+            }
+            "--version" => {
+                println!("{}", Version::current());
+                exit(0);
+            }
+            _ => listen_addresses.push(arg)
+        }
+    }
+    // Before opening the storage, migrations and everything after them are logged
+    let log_format_name = log_format.clone();
+    let log_format = match log_format {
+        Some(format) => format.parse(),
+        None => LogFormat::from_env()
+    };
+    match log_format {
+        Ok(format) => init_logging(format),
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    }
+    info!("Mimir tracker {}", env!("CARGO_PKG_VERSION"));
+    // Both work with the --db file only, importing into a memory DB or exporting an empty one makes no sense
+    if dry_run && (import_path.is_some() || export_csv_path.is_some()) {
+        error!("--import and --export-csv can't be used with --dry-run");
+        exit(1);
+    }
+    // Imports into the database and exits, the server is not started
+    if let Some(path) = import_path {
+        let storage = SqliteStorage::new(db_path.as_deref().unwrap_or(DEFAULT_DB_PATH));
+        match storage.import_from_json(&path, skip_sig_check) {
+            Ok(report) => {
+                info!("Imported {} addresses, skipped {} expired and {} with wrong signature, {} errors",
+                      report.inserted, report.skipped_expired, report.skipped_invalid_sig, report.errors);
+                exit(0);
+            }
+            Err(e) => {
+                error!("Error importing {}: {}", path, e);
+                exit(1);
+            }
+        }
+    }
+    // Exports the database and exits, the server is not started
+    if let Some(path) = export_csv_path {
+        let storage = SqliteStorage::new(db_path.as_deref().unwrap_or(DEFAULT_DB_PATH));
+        let result = File::create(&path).map_err(MimirError::from).and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            let rows = storage.export_csv(&mut writer)?;
+            writer.flush()?;
+            Ok(rows)
+        });
+        match result {
+            Ok(rows) => {
+                info!("Exported {} addresses to {}", rows, path);
+                exit(0);
+            }
+            Err(e) => {
+                error!("Error exporting to {}: {}", path, e);
+                exit(1);
+            }
+        }
+    }
+    if listen_addresses.is_empty() {
+        listen_addresses = config.listen.clone();
     }
+    let listen_address = match listen_addresses.first() {
+        Some(address) => address.clone(),
+        None => {
+            println!("Usage: ./tracker [--config file.toml] [--dry-run] [--storage sqlite|memory] [--db path|:memory:] [--version] [--log-format json|text] [--response-ttl secs] [--cleanup-on-startup] [--cleanup-interval secs] [--vacuum-on-startup] [--no-local-subnet-boost] [--sign-responses] [--pcap file] [--bind-device ifname] [--workers n] [--ban ip/prefix] [--reject-id hex_id] [--peer address:port] [--max-public-priority n] [--trusted-subnet ip/prefix] [--trusted-ip ip/prefix] [--reject-privileged-ports] [--lookup-notifications] [--reject-replayed-nonces] [--max-registrations-per-minute n] [--max-addresses-per-id n] [--max-time-skew secs] [--min-protocol-version n] [--report-top-ips secs] [--metrics] [--metrics-port port] [--watchdog-timeout secs] [--no-watchdog] [--shutdown-timeout secs] [--import file.ndjson [--skip-sig-check]] [--export-csv file.csv] [IPv6]:port [more addresses...]");
+            exit(0);
+        }
+    };
+    for address in listen_addresses.iter() {
+        if let Err(e) = parse_listen_addr(address) {
+            error!("Wrong listen address {}: {}, expected [IPv6]:port like [::1]:5050", address, e);
+            exit(1);
+        }
+    }
+    // Values are checked below, the settings are printed only if all of them are right
+    let effective_config = Config {
+        listen: listen_addresses.clone(),
+        storage: storage_backend.clone(),
+        db: db_path.clone(),
+        log_format: log_format_name,
+        response_ttl: parsed(&response_ttl),
+        cleanup_on_startup,
+        cleanup_interval: parsed(&cleanup_interval),
+        vacuum_on_startup,
+        local_subnet_boost: Some(local_subnet_boost),
+        sign_responses,
+        pcap: pcap_path.clone(),
+        bind_device: bind_device.clone(),
+        workers: parsed(&workers),
+        bans: bans.clone(),
+        reject_ids: rejected_ids.clone(),
+        peers: peers.clone(),
+        max_public_priority: parsed(&max_public_priority),
+        trusted_subnets: trusted_subnets.clone(),
+        trusted_ips: trusted_ips.clone(),
+        reject_privileged_ports,
+        lookup_notifications,
+        reject_replayed_nonces,
+        max_registrations_per_minute: parsed(&max_registrations),
+        max_addresses_per_id: parsed(&max_addresses_per_id),
+        max_time_skew: parsed(&max_time_skew),
+        min_protocol_version: parsed(&min_protocol_version),
+        report_top_ips: parsed(&report_top_ips),
+        metrics,
+        metrics_port: parsed(&metrics_port),
+        watchdog_timeout: parsed(&watchdog_timeout),
+        watchdog: Some(watchdog),
+        shutdown_timeout: parsed(&shutdown_timeout)
+    };
 
-    let server = Server::new(&args[1]);
-    server
-        .start()
-        .join()
-        .expect("Could not join main server thread!");
+    let mut server = Server::new(&listen_address)
+        .with_cleanup_on_startup(cleanup_on_startup)
+        .with_vacuum_on_startup(vacuum_on_startup)
+        .with_local_subnet_boost(local_subnet_boost)
+        .with_signed_responses(sign_responses)
+        .with_reject_privileged_ports(reject_privileged_ports)
+        .with_lookup_notifications(lookup_notifications)
+        .with_reject_replayed_nonces(reject_replayed_nonces);
+    if let Some(ttl) = response_ttl {
+        match ttl.parse() {
+            Ok(ttl) => server = server.with_response_ttl(Some(ttl)),
+            Err(_) => {
+                error!("Wrong --response-ttl value: {}", ttl);
+                exit(1);
+            }
+        }
+    }
+    if let Some(max) = max_registrations {
+        match max.parse() {
+            Ok(max) => server = server.with_rate_limiter(RateLimiter::new(max)),
+            Err(_) => {
+                error!("Wrong --max-registrations-per-minute value: {}", max);
+                exit(1);
+            }
+        }
+    }
+    if let Some(max) = max_addresses_per_id {
+        match max.parse::<u64>() {
+            Ok(max) if max > 0 => server = server.with_max_addresses_per_id(Some(max)),
+            _ => {
+                error!("Wrong --max-addresses-per-id value: {}", max);
+                exit(1);
+            }
+        }
+    }
+    if let Some(interval) = cleanup_interval {
+        // 0 turns periodic cleanup off
+        match interval.parse::<u64>() {
+            Ok(0) => server = server.with_cleanup_interval(None),
+            Ok(secs) => server = server.with_cleanup_interval(Some(Duration::from_secs(secs))),
+            Err(_) => {
+                error!("Wrong --cleanup-interval value: {}", interval);
+                exit(1);
+            }
+        }
+    }
+    if let Some(skew) = max_time_skew {
+        match skew.parse() {
+            Ok(skew) => server = server.with_max_time_skew(skew),
+            Err(_) => {
+                error!("Wrong --max-time-skew value: {}", skew);
+                exit(1);
+            }
+        }
+    }
+    if let Some(timeout) = watchdog_timeout {
+        match timeout.parse::<u64>() {
+            Ok(timeout) if timeout > 0 => server = server.with_watchdog_timeout(Some(Duration::from_secs(timeout))),
+            _ => {
+                error!("Wrong --watchdog-timeout value: {}", timeout);
+                exit(1);
+            }
+        }
+    }
+    if !watchdog {
+        server = server.with_watchdog_timeout(None);
+    }
+    let shutdown_timeout = match shutdown_timeout {
+        Some(timeout) => match timeout.parse::<u64>() {
+            Ok(timeout) => Duration::from_secs(timeout),
+            Err(_) => {
+                error!("Wrong --shutdown-timeout value: {}", timeout);
+                exit(1);
+            }
+        },
+        None => DEFAULT_SHUTDOWN_TIMEOUT
+    };
+    if let Some(interval) = report_top_ips {
+        match interval.parse::<u64>() {
+            Ok(interval) if interval > 0 => {
+                let metrics = Arc::new(ConnectionMetrics::new());
+                server = server.with_connection_metrics(Arc::clone(&metrics));
+                thread::spawn(move || print_top_ips(&metrics, Duration::from_secs(interval)));
+            }
+            _ => {
+                error!("Wrong --report-top-ips value: {}", interval);
+                exit(1);
+            }
+        }
+    }
+    // Shared by the metrics server and StatsD
+    let tracker_metrics = Arc::new(TrackerMetrics::new());
+    if metrics || metrics_port.is_some() {
+        // Next to the first listen address by default, like [::1]:5050 and [::1]:5051
+        let listen = parse_listen_addr(&listen_address).unwrap();
+        let port = match &metrics_port {
+            Some(port) => port.parse::<u16>().ok(),
+            None => listen.port().checked_add(1)
+        };
+        let Some(port) = port.filter(|port| *port != 0) else {
+            error!("Wrong --metrics-port value: {}", metrics_port.unwrap_or_default());
+            exit(1);
+        };
+        let addr = SocketAddr::V6(SocketAddrV6::new(*listen.ip(), port, 0, 0));
+        match MetricsServer::bind(addr, Arc::clone(&tracker_metrics)) {
+            Ok(metrics_server) => {
+                info!("Serving metrics on http://{}/metrics", addr);
+                metrics_server.start();
+            }
+            Err(e) => {
+                error!("Unable to serve metrics on {}: {}", addr, e);
+                exit(1);
+            }
+        }
+        server = server.with_metrics(Arc::clone(&tracker_metrics));
+    }
+    #[cfg(feature = "statsd")]
+    match StatsdEmitter::from_env(Arc::clone(&tracker_metrics)) {
+        Ok(statsd) => {
+            let statsd = Arc::new(statsd);
+            statsd.start(DEFAULT_STATSD_INTERVAL);
+            info!("Sending StatsD metrics to {}", env::var(STATSD_ADDR_ENV).unwrap_or_else(|_| DEFAULT_STATSD_ADDR.to_owned()));
+            server = server.with_metrics(Arc::clone(&tracker_metrics)).with_statsd(statsd);
+        }
+        Err(e) => {
+            error!("Unable to send StatsD metrics: {}", e);
+            exit(1);
+        }
+    }
+    for ip_cidr in bans {
+        if let Err(e) = ip_cidr.parse::<IpNet>() {
+            error!("Wrong --ban value: {}", e);
+            exit(1);
+        }
+        server = server.with_ban(&ip_cidr);
+    }
+    let peers: Vec<SocketAddr> = peers.iter().map(|peer| match peer.parse() {
+        Ok(peer) => peer,
+        Err(_) => {
+            error!("Wrong --peer value: {}", peer);
+            exit(1);
+        }
+    }).collect();
+    for hex_id in rejected_ids {
+        match from_hex_array::<32>(&hex_id) {
+            Some(id) => server = server.with_rejected_id(&id),
+            None => {
+                error!("Wrong --reject-id value: {}", hex_id);
+                exit(1);
+            }
+        }
+    }
+    if let Some(max) = max_public_priority {
+        match max.parse() {
+            Ok(max) => server = server.with_max_priority_from_public_ips(max),
+            Err(_) => {
+                error!("Wrong --max-public-priority value: {}", max);
+                exit(1);
+            }
+        }
+    }
+    for subnet in trusted_subnets {
+        match subnet.parse::<IpNet>() {
+            Ok(subnet) => server = server.with_trusted_subnet(subnet),
+            Err(e) => {
+                error!("Wrong --trusted-subnet value: {}", e);
+                exit(1);
+            }
+        }
+    }
+    for range in trusted_ips {
+        match range.parse::<IpNet>() {
+            Ok(range) => server = server.with_trusted_ip_range(range),
+            Err(e) => {
+                error!("Wrong --trusted-ip value: {}", e);
+                exit(1);
+            }
+        }
+    }
+    if let Some(min) = min_protocol_version {
+        // Older clients get ErrorCode::UnsupportedVersion with our version, so they can ask users to update
+        match min.parse::<u8>() {
+            Ok(min) if min <= PROTOCOL_VERSION => server = server.with_protocol_versions(min, PROTOCOL_VERSION),
+            _ => {
+                error!("Wrong --min-protocol-version value: {}", min);
+                exit(1);
+            }
+        }
+    }
+    if let Some(count) = workers {
+        match count.parse::<usize>() {
+            Ok(count) if count > 0 => server = server.with_workers(count),
+            _ => {
+                error!("Wrong --workers value: {}", count);
+                exit(1);
+            }
+        }
+    }
+    if let Some(device) = bind_device {
+        server = server.with_bind_device(&device);
+    }
+    if let Some(path) = pcap_path {
+        match PacketCapture::new(&path) {
+            Ok(capture) => server = server.with_capture(capture),
+            Err(e) => {
+                error!("Unable to create capture file {}: {}", path, e);
+                exit(1);
+            }
+        }
+    }
+    let backend = match storage_backend {
+        Some(name) => name.parse(),
+        None => Ok(StorageBackend::Sqlite)
+    };
+    let backend = match backend {
+        Ok(_) if dry_run => {
+            warn!("[DRY RUN - no data will be persisted]");
+            StorageBackend::Memory
+        }
+        Ok(backend) => backend,
+        Err(e) => {
+            error!("{}", e);
+            exit(1);
+        }
+    };
+    // ":memory:" runs an ephemeral tracker with sqlite backend too, nothing is read from or written to disk
+    let storage = match backend.open(db_path.as_deref().unwrap_or(DEFAULT_DB_PATH)) {
+        Ok(storage) => storage,
+        Err(e) => {
+            error!("Unable to open storage: {}", e);
+            exit(1);
+        }
+    };
+    if let Some(federation) = start_federation(storage.as_ref(), peers) {
+        server = server.with_federation(federation);
+    }
+    server = server.with_storage(storage);
+    if let Some(path) = config_path {
+        info!("Loaded config {}", path);
+    }
+    let mut settings = effective_config.to_args();
+    settings.extend(effective_config.listen);
+    info!("Effective config: {}", settings.join(" "));
+    if let Err(e) = install_signal_handlers() {
+        warn!("Unable to handle SIGTERM and SIGINT, they will kill the tracker without flushing: {}", e);
+    }
+    let handles = server.listen_on_multiple(listen_addresses);
+    wait_for_shutdown(&handles);
+    info!("Shutting down, waiting up to {:?} for requests in progress", shutdown_timeout);
+    if !join_with_timeout(handles, shutdown_timeout) {
+        error!("Server threads did not stop in {:?}, exiting anyway", shutdown_timeout);
+        exit(1);
+    }
+}
+
+/// Loads the file of `--config`, or `DEFAULT_CONFIG_PATH` if it exists, returns the path of the loaded file
+fn load_config(command_line: &[String]) -> (Config, Option<String>) {
+    let path = match command_line.iter().position(|arg| arg == "--config") {
+        Some(index) => match command_line.get(index + 1) {
+            Some(path) => path.as_str(),
+            None => {
+                eprintln!("Wrong --config value");
+                exit(1);
+            }
+        },
+        None if Path::new(DEFAULT_CONFIG_PATH).exists() => DEFAULT_CONFIG_PATH,
+        None => return (Config::default(), None)
+    };
+    match Config::load(path) {
+        Ok(config) => (config, Some(path.to_owned())),
+        Err(e) => {
+            eprintln!("Unable to load config {}: {}", path, e);
+            exit(1);
+        }
+    }
+}
+
+/// Value of a flag that is checked later, `None` if it is not given or wrong
+fn parsed<T: FromStr>(value: &Option<String>) -> Option<T> {
+    value.as_deref().and_then(|value| value.parse().ok())
+}
+
+/// Adds `peers` to the peers saved before, keeps the reachable ones and saves them for the next start
+fn start_federation(storage: &dyn Storage, peers: Vec<SocketAddr>) -> Option<FederationManager> {
+    let mut federation = FederationManager::new(DEFAULT_PEER_TIMEOUT, DEFAULT_CACHE_SECS);
+    federation.load_peers(storage);
+    for peer in peers {
+        if !federation.peers().iter().any(|(addr, _)| *addr == peer) {
+            federation.add_peer(peer, 0);
+        }
+    }
+    if !federation.has_peers() {
+        return None;
+    }
+    federation.remove_unreachable_peers();
+    if let Err(e) = federation.save_peers(storage) {
+        error!("Error saving peer trackers: {}", e);
+    }
+    info!("Forwarding lookups to {} peer trackers", federation.peers().len());
+    Some(federation)
+}
+
+/// Prints 10 IPs with the most requests every `interval`
+fn print_top_ips(metrics: &ConnectionMetrics, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        for (ip, stats) in metrics.top_ips(10) {
+            info!("Top IP {}: {} requests, {} errors, {} registrations",
+                  ip, stats.request_count.load(Ordering::Relaxed), stats.error_count.load(Ordering::Relaxed), stats.registered_ids.load(Ordering::Relaxed));
+        }
+    }
+}
+
+/// Checks listen address before binding, to report typos instead of panicking
+fn parse_listen_addr(s: &str) -> Result<SocketAddrV6, MimirError> {
+    let addr: SocketAddrV6 = s.parse().map_err(|e| MimirError::InvalidData(format!("{}", e)))?;
+    if addr.port() == 0 {
+        return Err(MimirError::InvalidData("port must not be 0".to_owned()));
+    }
+    if addr.ip().is_multicast() || addr.ip().to_ipv4_mapped() == Some(Ipv4Addr::BROADCAST) {
+        return Err(MimirError::InvalidData("can't listen on broadcast or multicast address".to_owned()));
+    }
+    Ok(addr)
 }
@@ -0,0 +1,222 @@
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::num::NonZeroUsize;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use lru::LruCache;
+use tracing::{error, warn};
+use crate::storage::get_utc_time;
+
+/// IPs without requests for this many seconds are not reported
+const IP_IDLE_SECS: u64 = 3600;
+/// At most this many IPs are tracked, the ones without requests for the longest time are forgotten first
+const MAX_TRACKED_IPS: usize = 65536;
+/// Scrapers that don't send the request in this time are disconnected, only one is served at a time
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Counters of one source IP
+#[derive(Debug, Default)]
+pub struct IpStats {
+    pub request_count: AtomicU64,
+    /// Requests that were not answered because of an error
+    pub error_count: AtomicU64,
+    /// UTC time of the last request in seconds
+    pub last_seen: AtomicU64,
+    /// Accepted registrations, many IDs from one IP can be a bot
+    pub registered_ids: AtomicU64
+}
+
+/// Requests statistics by source IP, to find clients that poll too often or misbehave
+pub struct ConnectionMetrics {
+    ips: Mutex<LruCache<IpAddr, Arc<IpStats>>>
+}
+
+impl Default for ConnectionMetrics {
+    fn default() -> Self {
+        ConnectionMetrics { ips: Mutex::new(LruCache::new(NonZeroUsize::new(MAX_TRACKED_IPS).unwrap())) }
+    }
+}
+
+impl ConnectionMetrics {
+    pub fn new() -> Self {
+        ConnectionMetrics::default()
+    }
+
+    /// Counts a request from this IP
+    pub fn record_request(&self, ip: IpAddr) {
+        let stats = self.stats(ip);
+        stats.request_count.fetch_add(1, Ordering::Relaxed);
+        stats.last_seen.store(get_utc_time(), Ordering::Relaxed);
+    }
+
+    pub fn record_error(&self, ip: IpAddr) {
+        self.stats(ip).error_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_registration(&self, ip: IpAddr) {
+        self.stats(ip).registered_ids.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Gets up to `n` IPs with the most requests, busiest first
+    pub fn top_ips(&self, n: usize) -> Vec<(IpAddr, Arc<IpStats>)> {
+        let now = get_utc_time();
+        let ips = self.ips.lock().unwrap();
+        let mut result: Vec<_> = ips.iter()
+            .filter(|(_, stats)| now.saturating_sub(stats.last_seen.load(Ordering::Relaxed)) < IP_IDLE_SECS)
+            .map(|(ip, stats)| (*ip, Arc::clone(stats)))
+            .collect();
+        drop(ips);
+        result.sort_by_key(|(_, stats)| std::cmp::Reverse(stats.request_count.load(Ordering::Relaxed)));
+        result.truncate(n);
+        result
+    }
+
+    /// Counters are updated after the lock is released, it is held only to find them
+    fn stats(&self, ip: IpAddr) -> Arc<IpStats> {
+        let mut ips = self.ips.lock().unwrap();
+        // New IPs are seen now, or they would not be reported before the first request is counted
+        let stats = ips.get_or_insert(ip, || Arc::new(IpStats { last_seen: AtomicU64::new(get_utc_time()), ..IpStats::default() }));
+        Arc::clone(stats)
+    }
+}
+
+/// Counters of the whole tracker, shared by all server threads, `MetricsServer` and `StatsdEmitter`
+#[derive(Debug, Default)]
+pub struct TrackerMetrics {
+    /// Received requests, except the ones of banned IPs
+    pub requests: AtomicU64,
+    /// Accepted registrations (command 0)
+    pub registrations: AtomicU64,
+    /// Lookups of one or several IDs (commands 1 and 3)
+    pub queries: AtomicU64,
+    /// Requests that were not answered because of an error
+    pub errors: AtomicU64,
+    /// Saved addresses, updated on start and after every cleanup of expired ones
+    pub active_addresses: AtomicU64,
+    /// Registrations rejected by `RateLimiter`
+    pub rate_limited_registrations: AtomicU64
+}
+
+impl TrackerMetrics {
+    pub fn new() -> Self {
+        TrackerMetrics::default()
+    }
+
+    /// Name, Prometheus type, help and current value of every metric
+    pub fn values(&self) -> [(&'static str, &'static str, &'static str, u64); 6] {
+        [
+            ("mimir_requests_total", "counter", "Received requests", &self.requests),
+            ("mimir_registrations_total", "counter", "Accepted registrations", &self.registrations),
+            ("mimir_queries_total", "counter", "Lookups of one or several IDs", &self.queries),
+            ("mimir_errors_total", "counter", "Requests not answered because of an error", &self.errors),
+            ("mimir_active_addresses", "gauge", "Saved addresses after the last cleanup", &self.active_addresses),
+            ("mimir_rate_limited_registrations_total", "counter", "Registrations rejected by the rate limit", &self.rate_limited_registrations)
+        ].map(|(name, kind, help, value)| (name, kind, help, value.load(Ordering::Relaxed)))
+    }
+
+    /// Formats the counters in Prometheus text format
+    pub fn to_prometheus(&self) -> String {
+        let mut text = String::new();
+        for (name, kind, help, value) in self.values() {
+            text.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n{} {}\n", name, help, name, kind, name, value));
+        }
+        text
+    }
+}
+
+/// Serves `GET /metrics` over HTTP for Prometheus, in its own thread
+pub struct MetricsServer {
+    listener: TcpListener,
+    metrics: Arc<TrackerMetrics>
+}
+
+impl MetricsServer {
+    /// Binds the port right away, so that a busy port is reported before the tracker starts
+    pub fn bind(addr: SocketAddr, metrics: Arc<TrackerMetrics>) -> Result<Self, io::Error> {
+        Ok(MetricsServer { listener: TcpListener::bind(addr)?, metrics })
+    }
+
+    /// Answers scrapers until the process ends, a panic while answering one of them doesn't stop the tracker
+    pub fn start(self) -> JoinHandle<()> {
+        thread::spawn(move || {
+            for stream in self.listener.incoming() {
+                let Ok(stream) = stream else { continue };
+                let result = panic::catch_unwind(AssertUnwindSafe(|| answer_scrape(stream, &self.metrics)));
+                match result {
+                    Ok(Err(e)) => warn!("Error answering metrics request: {}", e),
+                    Err(_) => error!("Panic answering metrics request"),
+                    Ok(Ok(())) => {}
+                }
+            }
+        })
+    }
+}
+
+fn answer_scrape(mut stream: TcpStream, metrics: &TrackerMetrics) -> Result<(), io::Error> {
+    stream.set_read_timeout(Some(SCRAPE_TIMEOUT))?;
+    stream.set_write_timeout(Some(SCRAPE_TIMEOUT))?;
+    // Only the request line is needed, headers and body are ignored
+    let mut buf = [0u8; 1024];
+    let mut length = 0;
+    while length < buf.len() && !buf[..length].contains(&b'\n') {
+        match stream.read(&mut buf[length..])? {
+            0 => break,
+            read => length += read
+        }
+    }
+    let request = String::from_utf8_lossy(&buf[..length]);
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.to_prometheus()),
+        _ => ("404 Not Found", String::from("Not found\n"))
+    };
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body)?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv6Addr;
+
+    #[test]
+    fn top_ips_are_busiest_first() {
+        let metrics = ConnectionMetrics::new();
+        let (quiet, busy) = (IpAddr::V6(Ipv6Addr::from(1)), IpAddr::V6(Ipv6Addr::from(2)));
+        metrics.record_request(quiet);
+        for _ in 0..3 {
+            metrics.record_request(busy);
+        }
+        metrics.record_error(busy);
+        let top = metrics.top_ips(10);
+        assert_eq!(top.iter().map(|(ip, _)| *ip).collect::<Vec<_>>(), vec![busy, quiet]);
+        assert_eq!(top[0].1.request_count.load(Ordering::Relaxed), 3);
+        assert_eq!(top[0].1.error_count.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.top_ips(1).len(), 1);
+    }
+
+    #[test]
+    fn prometheus_text_has_all_counters() {
+        let metrics = TrackerMetrics::new();
+        metrics.registrations.store(3, Ordering::Relaxed);
+        metrics.rate_limited_registrations.store(2, Ordering::Relaxed);
+        let text = metrics.to_prometheus();
+        assert!(text.contains("# TYPE mimir_registrations_total counter\nmimir_registrations_total 3\n"));
+        assert!(text.contains("# TYPE mimir_rate_limited_registrations_total counter\nmimir_rate_limited_registrations_total 2\n"));
+        for name in ["mimir_requests_total", "mimir_queries_total", "mimir_errors_total", "mimir_active_addresses"] {
+            assert!(text.contains(&format!("\n{} 0\n", name)), "{}", name);
+        }
+    }
+
+    #[test]
+    fn tracks_bounded_number_of_ips() {
+        let metrics = ConnectionMetrics::new();
+        for n in 0..MAX_TRACKED_IPS as u128 + 10 {
+            metrics.record_request(IpAddr::V6(Ipv6Addr::from(n)));
+        }
+        assert_eq!(metrics.ips.lock().unwrap().len(), MAX_TRACKED_IPS);
+    }
+}
@@ -0,0 +1,65 @@
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use lru::LruCache;
+
+/// Packets with a nonce seen from the same IP this recently are replays
+pub const NONCE_WINDOW: Duration = Duration::from_secs(60);
+/// Enough for about 2200 requests per second over `NONCE_WINDOW`, around 13 MB with the LRU overhead.
+/// At higher rates the oldest nonces are forgotten before the window ends, memory stays bounded.
+const CAPACITY: usize = 131072;
+
+/// Remembers `(source IP, nonce)` of recent requests, to drop the same packet sent again
+pub struct NonceCache {
+    seen: Mutex<LruCache<(IpAddr, u32), Instant>>
+}
+
+impl Default for NonceCache {
+    fn default() -> Self {
+        NonceCache { seen: Mutex::new(LruCache::new(NonZeroUsize::new(CAPACITY).unwrap())) }
+    }
+}
+
+impl NonceCache {
+    pub fn new() -> Self {
+        NonceCache::default()
+    }
+
+    /// Remembers the nonce, returns false if it was already seen from this IP in `NONCE_WINDOW`
+    pub fn check(&self, ip: IpAddr, nonce: u32) -> bool {
+        let now = Instant::now();
+        let mut seen = self.seen.lock().unwrap();
+        match seen.put((ip, nonce), now) {
+            Some(seen_at) => now.duration_since(seen_at) >= NONCE_WINDOW,
+            None => true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv6Addr;
+
+    #[test]
+    fn rejects_nonce_seen_from_same_ip() {
+        let cache = NonceCache::new();
+        let (ip, other_ip) = (IpAddr::V6(Ipv6Addr::LOCALHOST), IpAddr::V6(Ipv6Addr::UNSPECIFIED));
+        assert!(cache.check(ip, 1));
+        assert!(!cache.check(ip, 1));
+        assert!(cache.check(ip, 2));
+        assert!(cache.check(other_ip, 1));
+    }
+
+    #[test]
+    fn forgets_oldest_nonces_at_capacity() {
+        let cache = NonceCache::new();
+        let ip = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        for nonce in 0..=CAPACITY as u32 {
+            assert!(cache.check(ip, nonce));
+        }
+        assert_eq!(cache.seen.lock().unwrap().len(), CAPACITY);
+        assert!(cache.check(ip, 0));
+    }
+}
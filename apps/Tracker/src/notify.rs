@@ -0,0 +1,76 @@
+use std::net::{Ipv6Addr, SocketAddr, SocketAddrV6, UdpSocket};
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use lru::LruCache;
+use tracing::warn;
+use crate::protocol::CMD_LOOKUP_NOTIFY;
+use crate::storage::{Addr, ClientId};
+use crate::version::PROTOCOL_VERSION;
+
+/// Number of addresses waiting for notifications, the least recently registered are forgotten first
+const CAPACITY: usize = 65536;
+/// One address gets at most one notification in this time, however often it is looked up
+const MIN_NOTIFY_INTERVAL: Duration = Duration::from_secs(1);
+
+struct Target {
+    addr: SocketAddr,
+    expires_at: Instant,
+    notified_at: Option<Instant>
+}
+
+/// Tells registered nodes that asked for it when their address is given out in a lookup,
+/// so that they can connect to the querier first. Push packets are `version` u8, `CMD_LOOKUP_NOTIFY`,
+/// `querier_id` [32] (zeros if the querier didn't send its ID) and `querier_ip` [16].
+pub struct UpstreamNotifier {
+    targets: Mutex<LruCache<([u8; 32], ClientId), Target>>
+}
+
+impl Default for UpstreamNotifier {
+    fn default() -> Self {
+        UpstreamNotifier { targets: Mutex::new(LruCache::new(NonZeroUsize::new(CAPACITY).unwrap())) }
+    }
+}
+
+impl UpstreamNotifier {
+    pub fn new() -> Self {
+        UpstreamNotifier::default()
+    }
+
+    /// Notifies `ip` and `port` of this ID and client about lookups for `ttl` seconds
+    pub fn register(&self, id: &[u8; 32], client: ClientId, ip: Ipv6Addr, port: u16, ttl: u64) {
+        let target = Target {
+            addr: SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0)),
+            expires_at: Instant::now() + Duration::from_secs(ttl),
+            notified_at: None
+        };
+        self.targets.lock().unwrap().put((*id, client), target);
+    }
+
+    pub fn unregister(&self, id: &[u8; 32], client: ClientId) {
+        self.targets.lock().unwrap().pop(&(*id, client));
+    }
+
+    /// Sends push packets to the given out addresses of `id` that asked for notifications
+    pub fn notify(&self, socket: &UdpSocket, id: &[u8; 32], addrs: &[Addr], querier_id: &[u8; 32], querier_ip: &[u8; 16]) {
+        let now = Instant::now();
+        let mut targets = self.targets.lock().unwrap();
+        for addr in addrs {
+            let Some(target) = targets.get_mut(&(*id, addr.client)) else { continue };
+            // Addresses from peer trackers can differ from the registered one
+            let matches = matches!(target.addr, SocketAddr::V6(a) if a.ip().octets().as_slice() == addr.ip.as_slice() && a.port() == addr.port);
+            if !matches || target.expires_at < now || target.notified_at.is_some_and(|at| now.duration_since(at) < MIN_NOTIFY_INTERVAL) {
+                continue;
+            }
+            target.notified_at = Some(now);
+            let mut packet = Vec::with_capacity(50);
+            packet.push(PROTOCOL_VERSION);
+            packet.push(CMD_LOOKUP_NOTIFY);
+            packet.extend_from_slice(querier_id);
+            packet.extend_from_slice(querier_ip);
+            if let Err(e) = socket.send_to(&packet, target.addr) {
+                warn!("Error notifying {}: {}", target.addr, e);
+            }
+        }
+    }
+}
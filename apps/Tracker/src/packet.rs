@@ -0,0 +1,58 @@
+use crate::error::MimirError;
+
+/// Reads fields of a request, reads past the end give `MimirError::MalformedPacket` with the position of the field
+pub struct SafeCursor<'a> {
+    data: &'a [u8],
+    position: usize
+}
+
+impl<'a> SafeCursor<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        SafeCursor { data, position: 0 }
+    }
+
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Number of bytes not read yet, optional fields are present if it is not 0
+    pub fn remaining(&self) -> usize {
+        self.data.len() - self.position
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], MimirError> {
+        if self.remaining() < length {
+            return Err(MimirError::MalformedPacket { position: self.position });
+        }
+        let bytes = &self.data[self.position..self.position + length];
+        self.position += length;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, MimirError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_u16_be(&mut self) -> Result<u16, MimirError> {
+        self.read_array().map(u16::from_be_bytes)
+    }
+
+    pub fn read_u32_be(&mut self) -> Result<u32, MimirError> {
+        self.read_array().map(u32::from_be_bytes)
+    }
+
+    pub fn read_u64_be(&mut self) -> Result<u64, MimirError> {
+        self.read_array().map(u64::from_be_bytes)
+    }
+
+    /// Reads fields of variable length, like the length of the field is sent before it
+    pub fn read_bytes(&mut self, length: usize) -> Result<&'a [u8], MimirError> {
+        self.take(length)
+    }
+
+    pub fn read_array<const N: usize>(&mut self) -> Result<[u8; N], MimirError> {
+        let mut array = [0u8; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+}
@@ -0,0 +1,107 @@
+use std::fmt::{Display, Formatter};
+use std::num::NonZeroU16;
+use serde::{Deserialize, Serialize};
+use crate::error::MimirError;
+use crate::storage::PortNum;
+
+/// Command byte of error answers, they carry `nonce`, this command, `ErrorCode` u8 and its payload
+pub const CMD_ERROR: u8 = 0xff;
+/// Set in the optional flags of command 1 when `client` u32 follows, only addresses of this client type are returned
+pub const LOOKUP_FLAG_CLIENT: u8 = 0x02;
+/// Set in the optional flags of command 1 when `max_age_secs` u32 follows (after `client`), 0 means no filter
+pub const LOOKUP_FLAG_MAX_AGE: u8 = 0x04;
+/// Set in the optional flags of command 1 when `client_ip` [16] of the asking node follows (after `max_age_secs`)
+pub const LOOKUP_FLAG_CLIENT_IP: u8 = 0x08;
+/// Set in the optional flags of command 1 when `min_priority` u8 and `max_priority` u8 follow (after `client_ip`)
+pub const LOOKUP_FLAG_PRIORITY: u8 = 0x10;
+/// Set in the optional flags of command 1 when `querier_id` [32] of the asking node follows (after `max_priority`)
+pub const LOOKUP_FLAG_QUERIER_ID: u8 = 0x20;
+/// Set in the optional flags of command 0 (after `latency_hint_ms`) to get `CMD_LOOKUP_NOTIFY` when the address is looked up
+pub const REGISTER_FLAG_NOTIFY_ON_LOOKUP: u8 = 0x01;
+/// Command byte of push packets that tell a registered node about a lookup of its address
+pub const CMD_LOOKUP_NOTIFY: u8 = 0xfd;
+/// Command 3 resolves at most this many IDs, they follow `count` u8 in the payload
+pub const MAX_BATCH_IDS: usize = 16;
+/// Set in the flags of command-3 answers when addresses or IDs didn't fit in the answer
+pub const BATCH_FLAG_TRUNCATED: u8 = 0x01;
+/// Set in the optional flags of command 2 to remove the address without grace period
+pub const FLAG_HARD_DELETE: u8 = 0x01;
+/// Set in the optional flags of command 2 when `deleted_at` u64 and tombstone signature follow, implies hard delete
+pub const FLAG_TOMBSTONE: u8 = 0x02;
+
+/// Command byte of requests, answers repeat it
+#[non_exhaustive]
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Command {
+    Register = 0,
+    Lookup = 1,
+    Deregister = 2,
+    /// Lookup of several IDs at once, the ID of the request header is ignored
+    BatchLookup = 3,
+    /// Answered with max protocol version and public key of the tracker if it signs responses
+    Ping = 5,
+    /// Command this version doesn't know, with its byte
+    Unknown(u8) = 0xff
+}
+
+impl Command {
+    pub const fn byte(self) -> u8 {
+        match self {
+            Command::Register => 0,
+            Command::Lookup => 1,
+            Command::Deregister => 2,
+            Command::BatchLookup => 3,
+            Command::Ping => 5,
+            Command::Unknown(byte) => byte
+        }
+    }
+}
+
+impl From<u8> for Command {
+    fn from(byte: u8) -> Self {
+        match byte {
+            0 => Command::Register,
+            1 => Command::Lookup,
+            2 => Command::Deregister,
+            3 => Command::BatchLookup,
+            5 => Command::Ping,
+            byte => Command::Unknown(byte)
+        }
+    }
+}
+
+/// Port of a registered address, nobody can connect to port 0
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Port(NonZeroU16);
+
+impl Port {
+    pub fn try_new(value: PortNum) -> Result<Port, InvalidPort> {
+        NonZeroU16::new(value).map(Port).ok_or(InvalidPort(value))
+    }
+
+    pub fn get(self) -> PortNum {
+        self.0.get()
+    }
+
+    /// Ports below 1024 need root to listen on most systems
+    pub fn is_privileged(self) -> bool {
+        self.get() < 1024
+    }
+}
+
+/// Port that can't be registered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InvalidPort(pub PortNum);
+
+impl Display for InvalidPort {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "port {} can't be registered", self.0)
+    }
+}
+
+impl From<InvalidPort> for MimirError {
+    fn from(e: InvalidPort) -> Self {
+        MimirError::InvalidData(e.to_string())
+    }
+}
@@ -0,0 +1,56 @@
+// Also compiled by build.rs, that checks every statement against the schema
+pub const SQL_CREATE_TABLES: &str = include_str!("create_db.sql");
+/// Schema changes after `create_db.sql`, the one at index N upgrades `user_version` N to N + 1
+pub const MIGRATIONS: &[&str] = &[
+    "ALTER TABLE clients ADD COLUMN latency_hint INTEGER DEFAULT 0;",
+    "ALTER TABLE clients ADD COLUMN flags INTEGER DEFAULT 0;",
+    "ALTER TABLE clients ADD COLUMN signed_at INTEGER DEFAULT 0;",
+    // Older versions could save two rows for one client in a race, the newest one is kept
+    "DELETE FROM clients WHERE rowid NOT IN (SELECT MAX(rowid) FROM clients GROUP BY id, client);
+     CREATE UNIQUE INDEX IF NOT EXISTS idx_clients_id_client ON clients (id, client);",
+    "DROP INDEX IF EXISTS idx_clients_ip;",
+];
+pub const SQL_GET_DB_VERSION: &str = "PRAGMA user_version";
+pub const SQL_SELECT_SAVED_ROW: &str = "SELECT ip, port, priority, timestamp, ttl FROM clients WHERE id=? AND client=?";
+pub const SQL_UPSERT_IP: &str = "INSERT INTO clients (id, ip, signature, port, priority, client, timestamp, ttl, latency_hint, flags, signed_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) ON CONFLICT (id, client) DO UPDATE SET ip=excluded.ip, signature=excluded.signature, port=excluded.port, priority=excluded.priority, timestamp=excluded.timestamp, ttl=excluded.ttl, latency_hint=excluded.latency_hint, flags=excluded.flags, signed_at=excluded.signed_at";
+/// The `VALUES` tuple is repeated for the number of rows, `flags` replace the saved ones like for a new address
+pub const SQL_BULK_UPSERT_IP: &str = "INSERT INTO clients (id, ip, signature, port, priority, client, timestamp, ttl, latency_hint, signed_at, flags) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?) ON CONFLICT (id, client) DO UPDATE SET ip=excluded.ip, signature=excluded.signature, port=excluded.port, priority=excluded.priority, timestamp=excluded.timestamp, ttl=excluded.ttl, latency_hint=excluded.latency_hint, flags=excluded.flags, signed_at=excluded.signed_at";
+pub const SQL_SOFT_DELETE_IP: &str = "UPDATE clients SET timestamp=?, ttl=?, flags=flags | ? WHERE id=? AND ip=? AND client=? AND timestamp + ttl > ?";
+pub const SQL_TOUCH_IP: &str = "UPDATE clients SET timestamp=?, ttl=? WHERE id=? AND ip=? AND client=?";
+pub const SQL_SELECT_IPS: &str = "SELECT ip, signature, port, priority, client, timestamp, ttl, latency_hint, flags, signed_at FROM clients WHERE id=? AND NOT EXISTS (SELECT 1 FROM tombstones t WHERE t.id = clients.id AND t.ip = clients.ip AND t.deleted_at > clients.timestamp)";
+pub const SQL_SELECT_IPS_LIMITED: &str = "SELECT ip, signature, port, priority, client, timestamp, ttl, latency_hint, flags, signed_at FROM clients WHERE id=? AND timestamp + ttl >= ? AND NOT EXISTS (SELECT 1 FROM tombstones t WHERE t.id = clients.id AND t.ip = clients.ip AND t.deleted_at > clients.timestamp) ORDER BY priority DESC LIMIT ?";
+pub const SQL_SELECT_IPS_MATCHING: &str = "SELECT ip, signature, port, priority, client, timestamp, ttl, latency_hint, flags, signed_at FROM clients WHERE id=? AND client BETWEEN ? AND ? AND priority BETWEEN ? AND ? AND timestamp > ? AND timestamp + ttl >= ? AND NOT EXISTS (SELECT 1 FROM tombstones t WHERE t.id = clients.id AND t.ip = clients.ip AND t.deleted_at > clients.timestamp) ORDER BY priority DESC LIMIT ?";
+/// `IN (?)` is repeated for the number of IDs, `id` is the last column to read addresses as from other selects
+pub const SQL_SELECT_IPS_FOR_IDS: &str = "SELECT ip, signature, port, priority, client, timestamp, ttl, latency_hint, flags, signed_at, id FROM clients WHERE id IN (?) AND timestamp + ttl >= ? AND NOT EXISTS (SELECT 1 FROM tombstones t WHERE t.id = clients.id AND t.ip = clients.ip AND t.deleted_at > clients.timestamp) ORDER BY priority DESC";
+pub const SQL_SELECT_ALL_IPS: &str = "SELECT id, ip, port, priority, client, timestamp, ttl, signature FROM clients ORDER BY id, client";
+pub const SQL_DELETE_ADDRESS: &str = "DELETE FROM clients WHERE id=? AND ip=? AND client=?";
+pub const SQL_DELETE_ID: &str = "DELETE FROM clients WHERE id=?";
+pub const SQL_SELECT_IDS: &str = "SELECT DISTINCT id FROM clients ORDER BY id LIMIT ? OFFSET ?";
+pub const SQL_SELECT_IDS_AFTER: &str = "SELECT DISTINCT id FROM clients WHERE id > ? ORDER BY id ASC LIMIT ?";
+pub const SQL_COUNT_IDS_FOR_IP: &str = "SELECT COUNT(DISTINCT id) FROM clients WHERE ip=? AND timestamp + ttl > ?";
+pub const SQL_COUNT_ADDRESSES_FOR_ID: &str = "SELECT COUNT(*) FROM clients WHERE id=? AND timestamp + ttl > ?";
+pub const SQL_COUNT_TOTAL: &str = "SELECT COUNT(*), COUNT(DISTINCT id) FROM clients";
+/// Deletes at most `LIMIT` rows, to not hold the connection for the whole table
+pub const SQL_DELETE_EXPIRED: &str = "DELETE FROM clients WHERE rowid IN (SELECT rowid FROM clients WHERE timestamp + ttl < ? LIMIT ?)";
+pub const SQL_INSERT_TOMBSTONE: &str = "INSERT INTO tombstones (id, ip, deleted_at, signature) VALUES (?, ?, ?, ?)";
+pub const SQL_DELETE_TOMBSTONED: &str = "DELETE FROM clients WHERE id=? AND ip=? AND timestamp < ?";
+pub const SQL_SELECT_TOMBSTONES_SINCE: &str = "SELECT id, ip, deleted_at, signature FROM tombstones WHERE deleted_at >= ? ORDER BY deleted_at";
+pub const SQL_DELETE_OLD_TOMBSTONES: &str = "DELETE FROM tombstones WHERE deleted_at < ?";
+pub const SQL_UPSERT_BAN: &str = "INSERT INTO bans (ip_cidr, banned_at, expires_at, reason) VALUES (?, ?, ?, ?) ON CONFLICT (ip_cidr) DO UPDATE SET banned_at=excluded.banned_at, expires_at=excluded.expires_at, reason=excluded.reason";
+pub const SQL_SELECT_BAN: &str = "SELECT ip_cidr, banned_at, expires_at, reason FROM bans WHERE ip_cidr=? AND expires_at > ?";
+pub const SQL_SELECT_ACTIVE_BANS: &str = "SELECT ip_cidr, banned_at, expires_at, reason FROM bans WHERE expires_at > ?";
+pub const SQL_DELETE_EXPIRED_BANS: &str = "DELETE FROM bans WHERE expires_at <= ?";
+pub const SQL_UPSERT_REJECTED_ID: &str = "INSERT INTO rejected_ids (id, rejected_at, reason) VALUES (?, ?, ?) ON CONFLICT (id) DO UPDATE SET rejected_at=excluded.rejected_at, reason=excluded.reason";
+pub const SQL_SELECT_REJECTED_IDS: &str = "SELECT id FROM rejected_ids";
+pub const SQL_SELECT_SETTING: &str = "SELECT value FROM settings WHERE name=?";
+pub const SQL_UPSERT_SETTING: &str = "INSERT INTO settings (name, value) VALUES (?, ?) ON CONFLICT (name) DO UPDATE SET value=excluded.value";
+pub const SQL_BEGIN: &str = "BEGIN";
+/// Takes the write lock at once, so nothing changes between reads and writes of the transaction
+pub const SQL_BEGIN_IMMEDIATE: &str = "BEGIN IMMEDIATE";
+pub const SQL_COMMIT: &str = "COMMIT";
+pub const SQL_ROLLBACK: &str = "ROLLBACK";
+pub const SQL_VACUUM: &str = "PRAGMA optimize; VACUUM;";
+/// Frees up to 1000 pages, so that it doesn't block the server for long
+pub const SQL_INCREMENTAL_VACUUM: &str = "PRAGMA incremental_vacuum(1000);";
+/// Moves all pages of the write-ahead log into the database file, does nothing in other journal modes
+pub const SQL_WAL_CHECKPOINT: &str = "PRAGMA wal_checkpoint(FULL);";
@@ -0,0 +1,79 @@
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+use lru::LruCache;
+
+/// At most this many IPs are tracked, the ones untouched for the longest time are forgotten first.
+/// Buckets untouched for a minute are full again, so forgetting them changes nothing.
+const MAX_TRACKED_IPS: usize = 65536;
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant
+}
+
+/// Limits how often one source IP can register addresses (command 0), protecting DB write performance
+pub struct RateLimiter {
+    max_registrations_per_minute: u32,
+    registrations: Mutex<LruCache<IpAddr, TokenBucket>>,
+    rate_limited_registrations: AtomicU64
+}
+
+impl RateLimiter {
+    pub fn new(max_registrations_per_minute: u32) -> Self {
+        RateLimiter {
+            max_registrations_per_minute,
+            registrations: Mutex::new(LruCache::new(NonZeroUsize::new(MAX_TRACKED_IPS).unwrap())),
+            rate_limited_registrations: AtomicU64::new(0)
+        }
+    }
+
+    /// Takes one token from the bucket of this IP, returns false if the IP is over the limit
+    pub fn check_registration(&self, ip: IpAddr) -> bool {
+        let capacity = self.max_registrations_per_minute as f64;
+        let now = Instant::now();
+        let mut buckets = self.registrations.lock().unwrap();
+        let bucket = buckets.get_or_insert_mut(ip, || TokenBucket { tokens: capacity, updated: now });
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * capacity / 60.0;
+        bucket.tokens = (bucket.tokens + refill).min(capacity);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return true;
+        }
+        self.rate_limited_registrations.fetch_add(1, Ordering::Relaxed);
+        false
+    }
+
+    /// Number of registrations rejected by this limiter, the server reports them as `mimir_rate_limited_registrations_total`
+    pub fn rate_limited_registrations_total(&self) -> u64 {
+        self.rate_limited_registrations.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv6Addr;
+
+    #[test]
+    fn limits_registrations_per_ip() {
+        let limiter = RateLimiter::new(3);
+        let ip = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        assert!((0..3).all(|_| limiter.check_registration(ip)));
+        assert!(!limiter.check_registration(ip));
+        assert_eq!(limiter.rate_limited_registrations_total(), 1);
+        assert!(limiter.check_registration(IpAddr::V6(Ipv6Addr::UNSPECIFIED)));
+    }
+
+    #[test]
+    fn tracks_bounded_number_of_ips() {
+        let limiter = RateLimiter::new(1);
+        for n in 0..MAX_TRACKED_IPS as u128 + 10 {
+            limiter.check_registration(IpAddr::V6(Ipv6Addr::from(n)));
+        }
+        assert_eq!(limiter.registrations.lock().unwrap().len(), MAX_TRACKED_IPS);
+    }
+}
@@ -0,0 +1,29 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use crate::error::MimirError;
+use crate::storage::{get_utc_time, Storage};
+
+/// IDs that are never registered, like keys of spam bots or compromised ones, saved in storage
+pub struct RejectList {
+    storage: Arc<dyn Storage>,
+    ids: Mutex<HashSet<Vec<u8>>>
+}
+
+impl RejectList {
+    /// Loads all rejected IDs from storage
+    pub fn load_all(storage: Arc<dyn Storage>) -> Self {
+        let ids = storage.get_rejected_ids().into_iter().collect();
+        RejectList { storage, ids: Mutex::new(ids) }
+    }
+
+    pub fn is_rejected(&self, id: &[u8]) -> bool {
+        self.ids.lock().unwrap().contains(id)
+    }
+
+    /// Rejects all following registrations of this ID, already saved addresses expire as usual
+    pub fn reject(&self, id: &[u8], reason: &str) -> Result<(), MimirError> {
+        self.storage.add_rejected_id(id, get_utc_time(), reason)?;
+        self.ids.lock().unwrap().insert(id.to_vec());
+        Ok(())
+    }
+}
@@ -0,0 +1,51 @@
+//! HEX encoding of byte fields for `#[serde(with = "crate::serde_hex")]`, keys and signatures stay readable in JSON
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serializer};
+use crate::functions::{from_hex, to_hex};
+
+pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&to_hex(bytes))
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let hex = String::deserialize(deserializer)?;
+    from_hex(&hex).ok_or_else(|| D::Error::custom(format!("not a HEX string: {}", hex)))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::de::DeserializeOwned;
+    use serde::Serialize;
+    use crate::protocol::{Command, InvalidPort, Port};
+    use crate::server::ErrorCode;
+    use crate::storage::{Addr, Tombstone};
+
+    fn round_trip<T: Serialize + DeserializeOwned + PartialEq + std::fmt::Debug>(value: T) -> String {
+        let json = serde_json::to_string(&value).unwrap();
+        assert_eq!(serde_json::from_str::<T>(&json).unwrap(), value, "{}", json);
+        json
+    }
+
+    #[test]
+    fn bytes_are_hex_strings() {
+        let addr = Addr { ip: vec![0x02, 0xab], signature: vec![0xff; 2], port: 5050, priority: 1, client: 7, ttl: 600, latency_hint_ms: 20, flags: 1, signed_at: 2 };
+        let json = round_trip(addr);
+        assert!(json.contains(r#""ip":"02AB""#) && json.contains(r#""signature":"FFFF""#) && json.contains(r#""port":5050"#), "{}", json);
+        let json = round_trip(Tombstone { id: vec![1; 2], ip: vec![2; 2], deleted_at: 100, signature: vec![3; 2] });
+        assert!(json.contains(r#""id":"0101""#) && json.contains(r#""deleted_at":100"#), "{}", json);
+        assert!(serde_json::from_str::<Tombstone>(r#"{"id":"0","ip":"","deleted_at":0,"signature":""}"#).is_err());
+    }
+
+    #[test]
+    fn protocol_types_round_trip() {
+        for command in [Command::Register, Command::Lookup, Command::Deregister, Command::BatchLookup, Command::Ping, Command::Unknown(0x42)] {
+            round_trip(command);
+        }
+        assert_eq!(round_trip(Port::try_new(5050).unwrap()), "5050");
+        assert!(serde_json::from_str::<Port>("0").is_err());
+        round_trip(InvalidPort(0));
+        for code in [ErrorCode::UnsupportedVersion, ErrorCode::RegistrationRejected, ErrorCode::RateLimited] {
+            round_trip(code);
+        }
+    }
+}
@@ -1,106 +1,1470 @@
-use std::io::{Cursor, Read, Write};
-use std::net::{Ipv6Addr, SocketAddr, UdpSocket};
+use std::io::{Cursor, Write};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
 use std::{io, thread};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use crate::functions::check_signature;
-use crate::storage::{SqliteStorage, Storage};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use byteorder::{BigEndian, WriteBytesExt};
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signer};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, field, info, info_span, warn};
+use crate::ban::{BanList, IpNet, DEFAULT_BAN_SECS};
+use crate::capture::{Direction, PacketCapture};
+use crate::error::MimirError;
+use crate::federation::FederationManager;
+use crate::functions::{check_ip_signature, check_registration_signature, check_signature, dedup_addrs, deregistration_signed_data, full_deregistration_signed_data, to_hex};
+use crate::hooks::RegistrationHook;
+use crate::metrics::{ConnectionMetrics, TrackerMetrics};
+use crate::nonce::NonceCache;
+use crate::notify::UpstreamNotifier;
+use crate::packet::SafeCursor;
+use crate::protocol::{Command, InvalidPort, Port, CMD_ERROR, FLAG_HARD_DELETE, FLAG_TOMBSTONE, LOOKUP_FLAG_CLIENT, LOOKUP_FLAG_CLIENT_IP, LOOKUP_FLAG_MAX_AGE, LOOKUP_FLAG_PRIORITY, LOOKUP_FLAG_QUERIER_ID, REGISTER_FLAG_NOTIFY_ON_LOOKUP, BATCH_FLAG_TRUNCATED, MAX_BATCH_IDS};
+use crate::ratelimit::RateLimiter;
+use crate::reject::RejectList;
+use crate::shutdown::{is_shutdown_requested, SHUTDOWN_POLL_INTERVAL};
+#[cfg(feature = "statsd")]
+use crate::statsd::StatsdEmitter;
+use crate::storage::{get_utc_time, Addr, AddressFilter, Priority, Registration, RegistrationAction, ADDR_FLAG_FULL_SIGNATURE, DEFAULT_TTL, SqliteStorage, Storage, Tombstone, UPDATE_TTL};
+use crate::watchdog::{WatchdogTimer, DEFAULT_WATCHDOG_TIMEOUT};
+use crate::version::{ADDR_FLAGS_VERSION, DEREGISTRATION_VERSION, LATENCY_HINT_VERSION, MIN_SUPPORTED_VERSION, PROTOCOL_VERSION, REGISTRATION_SIGNATURE_VERSION, REQUEST_TIMESTAMP_VERSION, Version};
 
+/// Used when command 1 asks for 0 results, 10 addresses of any version fit in the response buffer
+const DEFAULT_MAX_RESULTS: u8 = 10;
+/// Largest UDP payload that is never fragmented over IPv6
+pub const RESPONSE_BUFFER_SIZE: usize = 1232;
+/// Requests and tombstones with timestamps further from our clock are rejected as replays
+pub const DEFAULT_MAX_TIME_SKEW: u64 = 300;
+/// Addresses with this many first bytes equal to `client_ip` are in the same /48 subnet
+const SUBNET_PREFIX_LEN: usize = 6;
+/// Expired addresses, tombstones and bans are removed this often while the server runs
+pub const DEFAULT_CLEANUP_INTERVAL: Duration = Duration::from_secs(600);
+pub const DEFAULT_DB_PATH: &str = "mimir.sqlite";
+/// Addresses outside of trusted subnets are registered with at most this priority, clients use 3 by default
+pub const DEFAULT_MAX_PUBLIC_PRIORITY: u8 = 3;
+/// Name of the setting with secret key of this tracker, it signs command-1 answers
+const TRACKER_KEY_SETTING: &str = "tracker_secret_key";
+
+/// Reason of an error answer
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// Packet version is out of the accepted range, `max_supported_version` u8 follows
+    UnsupportedVersion = 1,
+    /// The ID is in the reject list or one of registration hooks rejected the address, no payload
+    RegistrationRejected = 2,
+    /// Too many registrations from this source IP, or the ID has `max_addresses_per_id` addresses, no payload.
+    /// Clients should wait before registering again.
+    RateLimited = 3
+}
+
+#[derive(Clone)]
 pub struct Server {
     listen_address: String,
+    db_path: String,
+    response_ttl: Option<u64>,
+    cleanup_on_startup: bool,
+    cleanup_interval: Option<Duration>,
+    vacuum_on_startup: bool,
+    federation: Option<Arc<FederationManager>>,
+    capture: Option<Arc<Mutex<PacketCapture>>>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    /// Registrations of new clients are refused when the ID has this many not expired addresses
+    max_addresses_per_id: Option<u64>,
+    connection_metrics: Option<Arc<ConnectionMetrics>>,
+    metrics: Option<Arc<TrackerMetrics>>,
+    #[cfg(feature = "statsd")]
+    statsd: Option<Arc<StatsdEmitter>>,
+    /// Injected storage, if not set `SqliteStorage` is opened at `db_path` when server starts
+    storage: Option<Arc<dyn Storage>>,
+    max_time_skew: u64,
+    /// Process is stopped if a server loop doesn't get to the next packet in this time
+    watchdog_timeout: Option<Duration>,
+    local_subnet_boost: bool,
+    /// Packets with versions outside of this range are answered with `ErrorCode::UnsupportedVersion`
+    min_protocol_version: u8,
+    max_protocol_version: u8,
+    sign_responses: bool,
+    /// Network interface all sockets are bound to, only on Linux
+    bind_device: Option<String>,
+    /// Threads receiving packets of every listen address
+    workers: usize,
+    /// Banned on start in addition to the bans saved in storage
+    initial_bans: Vec<String>,
+    /// Rejected on start in addition to the IDs saved in storage
+    initial_rejected_ids: Vec<[u8; 32]>,
+    max_priority_from_public_ips: Priority,
+    /// Addresses in these subnets keep the priority they are registered with
+    trusted_subnets: Vec<IpNet>,
+    /// Packets from these IPs are never rate limited or dropped by bans
+    trusted_ip_ranges: Vec<IpNet>,
+    reject_privileged_ports: bool,
+    lookup_notifications: bool,
+    reject_replayed_nonces: bool,
+    /// Called in order for every registration, the first error rejects it
+    registration_hooks: Vec<Arc<dyn RegistrationHook>>,
+    /// Loaded from storage when server starts
+    ban_list: Option<Arc<BanList>>,
+    /// Loaded from storage when server starts
+    reject_list: Option<Arc<RejectList>>,
+    /// Created when server starts if `lookup_notifications` is set
+    notifier: Option<Arc<UpstreamNotifier>>,
+    /// Created when server starts if `reject_replayed_nonces` is set
+    nonce_cache: Option<Arc<NonceCache>>,
+    /// Loaded from storage when server starts if `sign_responses` is set
+    response_key: Option<Arc<Keypair>>,
+    /// Time this server was created, cloned servers of listen addresses keep it
+    started_at: Instant
 }
 
 impl Server {
     pub fn new(listen_address: &str) -> Self {
-        Server { listen_address: listen_address.to_owned() }
+        Server {
+            listen_address: listen_address.to_owned(),
+            db_path: DEFAULT_DB_PATH.to_owned(),
+            response_ttl: Some(UPDATE_TTL),
+            cleanup_on_startup: false,
+            cleanup_interval: Some(DEFAULT_CLEANUP_INTERVAL),
+            vacuum_on_startup: false,
+            federation: None,
+            capture: None,
+            rate_limiter: None,
+            max_addresses_per_id: None,
+            connection_metrics: None,
+            metrics: None,
+            #[cfg(feature = "statsd")]
+            statsd: None,
+            storage: None,
+            max_time_skew: DEFAULT_MAX_TIME_SKEW,
+            watchdog_timeout: Some(DEFAULT_WATCHDOG_TIMEOUT),
+            local_subnet_boost: true,
+            min_protocol_version: MIN_SUPPORTED_VERSION,
+            max_protocol_version: PROTOCOL_VERSION,
+            sign_responses: false,
+            bind_device: None,
+            workers: 1,
+            initial_bans: Vec::new(),
+            initial_rejected_ids: Vec::new(),
+            max_priority_from_public_ips: DEFAULT_MAX_PUBLIC_PRIORITY,
+            trusted_subnets: Vec::new(),
+            trusted_ip_ranges: Vec::new(),
+            reject_privileged_ports: false,
+            lookup_notifications: false,
+            reject_replayed_nonces: false,
+            registration_hooks: Vec::new(),
+            ban_list: None,
+            reject_list: None,
+            notifier: None,
+            nonce_cache: None,
+            response_key: None,
+            started_at: Instant::now()
+        }
+    }
+
+    /// Creates server that works with given storage instead of `SqliteStorage`, `db_path` is ignored
+    pub fn new_with_storage<S: Storage + 'static>(listen_address: &str, storage: S) -> Self {
+        Server::new(listen_address).with_storage(Box::new(storage))
+    }
+
+    /// Uses given storage instead of `SqliteStorage`, `db_path` is ignored
+    pub fn with_storage(mut self, storage: Box<dyn Storage>) -> Self {
+        self.storage = Some(Arc::from(storage));
+        self
+    }
+
+    /// Sets the path of the SQLite database file
+    pub fn with_db_path(mut self, db_path: &str) -> Self {
+        self.db_path = db_path.to_owned();
+        self
     }
 
+    /// Sets the TTL returned to registering clients, that is how soon they re-register.
+    /// `None` returns the TTL the address is stored with. It is never more than the stored TTL.
+    pub fn with_response_ttl(mut self, response_ttl: Option<u64>) -> Self {
+        self.response_ttl = response_ttl;
+        self
+    }
+
+    /// Removes expired addresses before accepting requests
+    pub fn with_cleanup_on_startup(mut self, cleanup: bool) -> Self {
+        self.cleanup_on_startup = cleanup;
+        self
+    }
+
+    /// Sets how often expired records are removed in a background thread, `None` keeps them until restart with cleanup
+    pub fn with_cleanup_interval(mut self, interval: Option<Duration>) -> Self {
+        self.cleanup_interval = interval;
+        self
+    }
+
+    /// Compacts the database before accepting requests
+    pub fn with_vacuum_on_startup(mut self, vacuum: bool) -> Self {
+        self.vacuum_on_startup = vacuum;
+        self
+    }
+
+    /// Forwards lookups of IDs not registered here to peer trackers
+    pub fn with_federation(mut self, federation: FederationManager) -> Self {
+        self.federation = Some(Arc::new(federation));
+        self
+    }
+
+    /// Writes every received packet and response to a pcap file
+    pub fn with_capture(mut self, capture: PacketCapture) -> Self {
+        self.capture = Some(Arc::new(Mutex::new(capture)));
+        self
+    }
+
+    /// Limits registrations per source IP
+    pub fn with_rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(Arc::new(rate_limiter));
+        self
+    }
+
+    /// Limits not expired addresses saved for one ID, addresses that are already saved can still be updated
+    pub fn with_max_addresses_per_id(mut self, max: Option<u64>) -> Self {
+        self.max_addresses_per_id = max;
+        self
+    }
+
+    /// Sets how far in seconds `request_timestamp` of v2 requests can be from our clock
+    pub fn with_max_time_skew(mut self, secs: u64) -> Self {
+        self.max_time_skew = secs;
+        self
+    }
+
+    /// Aborts the process when a server loop stalls for `timeout`, like on a stuck DB write, to be restarted by systemd.
+    /// Set to `None` to never abort, `DEFAULT_WATCHDOG_TIMEOUT` is used by default.
+    pub fn with_watchdog_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.watchdog_timeout = timeout.filter(|timeout| !timeout.is_zero());
+        self
+    }
+
+    /// Puts addresses from the same /48 subnet as `client_ip` of lookups first, regardless of their priority
+    pub fn with_local_subnet_boost(mut self, boost: bool) -> Self {
+        self.local_subnet_boost = boost;
+        self
+    }
+
+    /// Sets the range of accepted protocol versions, both ends included
+    pub fn with_protocol_versions(mut self, min_version: u8, max_version: u8) -> Self {
+        self.min_protocol_version = min_version;
+        self.max_protocol_version = max_version;
+        self
+    }
+
+    /// Appends signature of the tracker key to command-1 answers, the key is generated on first start and kept in storage
+    pub fn with_signed_responses(mut self, sign: bool) -> Self {
+        self.sign_responses = sign;
+        self
+    }
+
+    /// Counts requests of every source IP in `metrics`, the caller keeps it to read the busiest IPs
+    pub fn with_connection_metrics(mut self, metrics: Arc<ConnectionMetrics>) -> Self {
+        self.connection_metrics = Some(metrics);
+        self
+    }
+
+    /// Counts registrations, lookups and errors of all listen addresses, see `MetricsServer`
+    pub fn with_metrics(mut self, metrics: Arc<TrackerMetrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Sends the processing time of every request to StatsD, counters are sent by `StatsdEmitter::start`
+    #[cfg(feature = "statsd")]
+    pub fn with_statsd(mut self, statsd: Arc<StatsdEmitter>) -> Self {
+        self.statsd = Some(statsd);
+        self
+    }
+
+    /// Sends and receives packets only through this network interface, like `eth0`.
+    /// Other systems than Linux don't support it, the interface is ignored there.
+    pub fn with_bind_device(mut self, device: &str) -> Self {
+        self.bind_device = Some(device.to_owned());
+        self
+    }
+
+    /// Processes packets of every listen address in this many threads. On Linux every thread has its own socket
+    /// with `SO_REUSEPORT` and the kernel spreads packets between them, elsewhere the threads share one socket.
+    pub fn with_workers(mut self, workers: usize) -> Self {
+        self.workers = workers.max(1);
+        self
+    }
+
+    /// Bans IP or subnet like `200:1234::/48` for `DEFAULT_BAN_SECS` when server starts, packets from it are dropped
+    pub fn with_ban(mut self, ip_cidr: &str) -> Self {
+        self.initial_bans.push(ip_cidr.to_owned());
+        self
+    }
+
+    /// Rejects all registrations of this ID when server starts, the rejection is saved in storage
+    pub fn with_rejected_id(mut self, id: &[u8; 32]) -> Self {
+        self.initial_rejected_ids.push(*id);
+        self
+    }
+
+    /// Sets the highest priority addresses outside of trusted subnets are saved with, higher ones are lowered to it
+    pub fn with_max_priority_from_public_ips(mut self, max_priority: Priority) -> Self {
+        self.max_priority_from_public_ips = max_priority;
+        self
+    }
+
+    /// Lets addresses in this subnet register with any priority
+    pub fn with_trusted_subnet(mut self, subnet: IpNet) -> Self {
+        self.trusted_subnets.push(subnet);
+        self
+    }
+
+    /// Exempts senders in this range, like own nodes or monitoring, from rate limiting and bans.
+    /// Unlike bans, trusted ranges can't be changed while the server runs.
+    pub fn with_trusted_ip_range(mut self, range: IpNet) -> Self {
+        self.trusted_ip_ranges.push(range);
+        self
+    }
+
+    /// Rejects registrations of ports below 1024, nodes running without root can't listen on them
+    pub fn with_reject_privileged_ports(mut self, reject: bool) -> Self {
+        self.reject_privileged_ports = reject;
+        self
+    }
+
+    /// Sends `CMD_LOOKUP_NOTIFY` to registered nodes that ask for it when their address is looked up.
+    /// Only addresses registered from the same IP are notified, so that packets can't be directed to others.
+    pub fn with_lookup_notifications(mut self, notify: bool) -> Self {
+        self.lookup_notifications = notify;
+        self
+    }
+
+    /// Drops requests that repeat a nonce seen from the same IP in the last `NONCE_WINDOW`.
+    /// Clients must use a new nonce for every retry, or retries are dropped too.
+    pub fn with_reject_replayed_nonces(mut self, reject: bool) -> Self {
+        self.reject_replayed_nonces = reject;
+        self
+    }
+
+    /// Adds custom check of registrations, called after the hooks added before
+    pub fn add_registration_hook(mut self, hook: Box<dyn RegistrationHook>) -> Self {
+        self.registration_hooks.push(Arc::from(hook));
+        self
+    }
+
+    /// Opens the storage and starts serving on `listen_address` in a new thread
     pub fn start(&self) -> JoinHandle<()> {
-        let addr = self.listen_address.clone();
-        thread::spawn(move || {
-            let socket = UdpSocket::bind(addr.clone()).expect(&format!("Unable to bind to {}", &addr));
-            println!("Started on {}", &addr);
-            let mut buf = [0u8; 1024];
-            let mut response = [0u8; 1024];
-            let storage= SqliteStorage::new("mimir.sqlite");
-
-            loop {
-                if let Ok((length, src)) = socket.recv_from(&mut buf) {
-                    match Self::process_message(&storage, &buf[..length], &mut response, src) {
-                        Ok(size) => {
-                            if let Err(e) = socket.send_to(&response[..size], src) {
-                                println!("Error sending response to {}: {}", src, e);
-                            }
+        self.listen_on_multiple(vec![self.listen_address.clone()]).remove(0)
+    }
+
+    /// Seconds since this server was created
+    pub fn uptime_secs(&self) -> u64 {
+        self.started_at.elapsed().as_secs()
+    }
+
+    /// Opens the storage and starts `workers` threads per address, `listen_address` of this server is not used.
+    /// All threads share the storage, rate limiter and other settings.
+    /// They stop when shutdown is requested, the last one flushes the storage.
+    pub fn listen_on_multiple(&self, addresses: Vec<String>) -> Vec<JoinHandle<()>> {
+        let storage = match &self.storage {
+            Some(storage) => Arc::clone(storage),
+            None => Arc::new(SqliteStorage::new(&self.db_path)) as Arc<dyn Storage>
+        };
+        self.prepare_storage(storage.as_ref());
+        let response_key = self.sign_responses.then(|| {
+            let keypair = load_or_create_keypair(storage.as_ref());
+            info!("Signing responses with key {}", to_hex(keypair.public.as_bytes()));
+            Arc::new(keypair)
+        });
+        let ban_list = Arc::new(BanList::load_all_active(Arc::clone(&storage)));
+        for ip_cidr in self.initial_bans.iter() {
+            if let Err(e) = ban_list.ban(ip_cidr, DEFAULT_BAN_SECS, "command line") {
+                error!("Error banning {}: {}", ip_cidr, e);
+            }
+        }
+        let reject_list = Arc::new(RejectList::load_all(Arc::clone(&storage)));
+        for id in self.initial_rejected_ids.iter() {
+            if let Err(e) = reject_list.reject(id, "command line") {
+                error!("Error rejecting {}: {}", to_hex(id), e);
+            }
+        }
+        let notifier = self.lookup_notifications.then(|| Arc::new(UpstreamNotifier::new()));
+        let nonce_cache = self.reject_replayed_nonces.then(|| Arc::new(NonceCache::new()));
+        if let Some(metrics) = &self.metrics {
+            metrics.active_addresses.store(storage.count_total().0, Ordering::Relaxed);
+        }
+        if let Some(interval) = self.cleanup_interval {
+            let (storage, metrics) = (Arc::clone(&storage), self.metrics.clone());
+            thread::spawn(move || cleanup_periodically(storage.as_ref(), metrics.as_deref(), interval));
+        }
+        // Workers don't wait for peers, they answer from the cache while this thread asks them
+        if let Some(federation) = self.federation.as_ref().filter(|federation| federation.has_peers()) {
+            federation.start_forwarding();
+        }
+        let mut server = self.clone();
+        server.response_key = response_key;
+        server.ban_list = Some(ban_list);
+        server.reject_list = Some(reject_list);
+        server.notifier = notifier;
+        server.nonce_cache = nonce_cache;
+        let sockets: Vec<_> = addresses
+            .into_iter()
+            .flat_map(|addr| {
+                let sockets = bind_sockets(&addr, self.workers).unwrap_or_else(|e| panic!("Unable to bind to {}: {}", addr, e));
+                sockets.into_iter().map(move |socket| (addr.clone(), socket))
+            })
+            .collect();
+        let running = Arc::new(AtomicUsize::new(sockets.len()));
+        sockets
+            .into_iter()
+            .map(|(addr, socket)| {
+                if let Some(device) = &self.bind_device {
+                    bind_to_device(&socket, device).unwrap_or_else(|e| panic!("Unable to bind {} to device {}: {}", addr, device, e));
+                }
+                let (server, storage, running) = (server.clone(), Arc::clone(&storage), Arc::clone(&running));
+                thread::spawn(move || {
+                    server.serve(socket, &addr, storage.as_ref());
+                    // Other threads may still be writing until the last one is done
+                    if running.fetch_sub(1, Ordering::AcqRel) == 1 {
+                        match storage.flush() {
+                            Ok(_) => info!("Flushed storage"),
+                            Err(e) => error!("Error flushing storage: {}", e)
+                        }
+                    }
+                })
+            })
+            .collect()
+    }
+
+    fn serve(&self, socket: UdpSocket, addr: &str, storage: &dyn Storage) {
+        let local = socket.local_addr().expect("Error getting local address");
+        info!("Started on {}", addr);
+        let mut buf = [0u8; 1024];
+        let mut response = [0u8; RESPONSE_BUFFER_SIZE];
+        // Idle loops wake up to see shutdown requests, and to kick the watchdog as waiting for packets is not a stall
+        let read_timeout = self.watchdog_timeout.map_or(SHUTDOWN_POLL_INTERVAL, |timeout| (timeout / 4).min(SHUTDOWN_POLL_INTERVAL));
+        socket.set_read_timeout(Some(read_timeout)).expect("Error setting socket timeout");
+        let watchdog = self.watchdog_timeout.map(|timeout| WatchdogTimer::start(addr, timeout));
+
+        // Requests are processed to the end, so nothing is half written when the loop stops
+        while !is_shutdown_requested() {
+            if let Some(watchdog) = &watchdog {
+                watchdog.kick();
+            }
+            if let Ok((length, src)) = socket.recv_from(&mut buf) {
+                if !self.is_trusted(src.ip()) && self.ban_list.as_ref().is_some_and(|bans| bans.is_banned(src.ip())) {
+                    debug!(src_ip = %src.ip(), "Dropped packet from banned IP");
+                    continue;
+                }
+                if let Some(metrics) = &self.metrics {
+                    metrics.requests.fetch_add(1, Ordering::Relaxed);
+                }
+                if let Some(metrics) = &self.connection_metrics {
+                    metrics.record_request(src.ip());
+                }
+                self.capture_packet(Direction::Incoming, src, local, &buf[..length]);
+                #[cfg(feature = "statsd")]
+                let started = Instant::now();
+                let result = self.process_message(storage, &socket, &buf[..length], &mut response, src);
+                #[cfg(feature = "statsd")]
+                if let Some(statsd) = &self.statsd {
+                    statsd.record_request_duration(started.elapsed());
+                }
+                match result {
+                    Ok(size) => {
+                        self.capture_packet(Direction::Outgoing, local, src, &response[..size]);
+                        if let Err(e) = socket.send_to(&response[..size], src) {
+                            warn!(src_ip = %src.ip(), "Error sending response: {}", e);
                         }
-                        Err(e) => {
-                            println!("Error processing message: {:?}", e);
+                    }
+                    Err(e) => {
+                        warn!(src_ip = %src.ip(), "Error processing message: {}", e);
+                        if let Some(metrics) = &self.metrics {
+                            metrics.errors.fetch_add(1, Ordering::Relaxed);
+                        }
+                        if let Some(metrics) = &self.connection_metrics {
+                            metrics.record_error(src.ip());
                         }
                     }
                 }
             }
-        })
+        }
+        info!("Stopped on {}", addr);
+    }
+
+    /// Number of addresses that fit in a command-1 answer of this protocol version, with its signature if it is signed
+    fn max_lookup_results(&self, version: u8) -> usize {
+        let header_size = 4 + 1 + 1;
+        let signature_size = if self.response_key.is_some() { 64 } else { 0 };
+        ((RESPONSE_BUFFER_SIZE - header_size - signature_size) / addr_size(version)).min(u8::MAX as usize)
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_ip_ranges.iter().any(|range| range.contains(ip))
+    }
+
+    fn capture_packet(&self, dir: Direction, src: SocketAddr, dst: SocketAddr, payload: &[u8]) {
+        if let Some(capture) = &self.capture {
+            let ts = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            let mut capture = capture.lock().unwrap();
+            if let Err(e) = capture.record(dir, src, dst, payload, ts).and_then(|_| capture.flush()) {
+                error!("Error writing packet capture: {}", e);
+            }
+        }
+    }
+
+    fn prepare_storage(&self, storage: &dyn Storage) {
+        if self.cleanup_on_startup {
+            info!("Removing expired addresses...");
+            let start = Instant::now();
+            let removed = storage.cleanup_expired();
+            info!("Removed {} expired addresses in {:?}", removed, start.elapsed());
+        }
+        if self.vacuum_on_startup {
+            info!("Compacting database, all operations are blocked until it is done...");
+            let start = Instant::now();
+            match storage.vacuum(true) {
+                Ok(_) => info!("Compacted database in {:?}", start.elapsed()),
+                Err(e) => error!("Error compacting database: {}", e)
+            }
+        }
     }
 
-    fn process_message(storage: &SqliteStorage, data: &[u8], response: &mut [u8], src: SocketAddr) -> Result<usize, io::Error> {
-        let mut c = Cursor::new(data);
-        let _version = c.read_u8()?;
-        let nonce = c.read_u32::<BigEndian>()?;
-        let command = c.read_u8()?;
-        let mut id = [0u8; 32];
-        c.read_exact(&mut id)?;
-        let hex = to_hex(&id);
-        println!("Got command {} from/for {} on {}", command, &hex, &src.ip());
+    /// Answers the request in `data` from `src`, `socket` is used only for lookup notifications
+    fn process_message(&self, storage: &dyn Storage, socket: &UdpSocket, data: &[u8], response: &mut [u8], src: SocketAddr) -> Result<usize, MimirError> {
+        // Parent of storage spans, to see how much of the processing time is spent in the DB.
+        // Its fields are attached to every event logged while the request is processed.
+        let span = info_span!("process_message", src_ip = %src.ip(), nonce = field::Empty, command = field::Empty, id_hex = field::Empty);
+        let _enter = span.enter();
+        let mut c = SafeCursor::new(data);
+        let version = c.read_u8()?;
+        let nonce = c.read_u32_be()?;
+        span.record("nonce", nonce);
+        if version < self.min_protocol_version || version > self.max_protocol_version {
+            warn!("Unsupported protocol version {}", version);
+            return Ok(write_error(response, nonce, ErrorCode::UnsupportedVersion, &[self.max_protocol_version])?)
+        }
+        // Older clients don't send it and sign only ip
+        let request_timestamp = if version >= REQUEST_TIMESTAMP_VERSION {
+            let request_timestamp = c.read_u32_be()?;
+            if get_utc_time().abs_diff(request_timestamp as u64) > self.max_time_skew {
+                warn!("Request is too old or too new");
+                return Err(MimirError::InvalidData("request timestamp is out of allowed skew".to_owned()))
+            }
+            request_timestamp
+        } else {
+            0
+        };
+        // Checked after the timestamp, v2 packets older than the skew can't be replayed anyway
+        if self.nonce_cache.as_ref().is_some_and(|cache| !cache.check(src.ip(), nonce)) {
+            warn!("Replayed nonce");
+            return Err(MimirError::InvalidData("replayed nonce".to_owned()))
+        }
+        let command = Command::from(c.read_u8()?);
+        span.record("command", command.byte());
+        let id: [u8; 32] = c.read_array()?;
+        span.record("id_hex", to_hex(&id).as_str());
+        debug!("Got command");
         match command {
-            0 => {
-                let port = c.read_u16::<BigEndian>()?;
+            Command::Register => {
+                if let Some(limiter) = self.rate_limiter.as_ref().filter(|_| !self.is_trusted(src.ip())) {
+                    if !limiter.check_registration(src.ip()) {
+                        warn!("Too many registrations");
+                        if let Some(metrics) = &self.metrics {
+                            metrics.rate_limited_registrations.fetch_add(1, Ordering::Relaxed);
+                        }
+                        return Ok(write_error(response, nonce, ErrorCode::RateLimited, &[])?)
+                    }
+                }
+                // Checked before the signature, to spend no time on IDs that are never saved
+                if self.reject_list.as_ref().is_some_and(|list| list.is_rejected(&id)) {
+                    warn!("Registration of rejected ID");
+                    return Ok(write_error(response, nonce, ErrorCode::RegistrationRejected, &[])?)
+                }
+                let port = Port::try_new(c.read_u16_be()?)?;
+                if self.reject_privileged_ports && port.is_privileged() {
+                    return Err(InvalidPort(port.get()).into())
+                }
+                let port = port.get();
                 let priority = c.read_u8()?;
-                let client = c.read_u32::<BigEndian>()?;
-                let mut ip = [0u8; 16];
-                c.read_exact(&mut ip)?;
-                let mut signature = [0u8; 64];
-                c.read_exact(&mut signature)?;
-                if !check_signature(&id, &signature, &ip) {
+                let client = c.read_u32_be()?;
+                let ip: [u8; 16] = c.read_array()?;
+                let signature: [u8; 64] = c.read_array()?;
+                // Optional, older clients don't measure latency
+                let latency_hint_ms = if c.remaining() > 0 {
+                    c.read_u16_be()?
+                } else {
+                    0
+                };
+                let register_flags = if c.remaining() > 0 { c.read_u8()? } else { 0 };
+                let full_signature = version >= REGISTRATION_SIGNATURE_VERSION;
+                let signature_ok = match full_signature {
+                    true => check_registration_signature(&id, &ip, port, priority, client, request_timestamp, &signature),
+                    false => check_ip_signature(&id, &signature, &ip, request_timestamp)
+                };
+                if !signature_ok {
                     let ip = Ipv6Addr::from(ip);
-                    println!("Wrong signature from {} for {}", &ip, &hex);
-                    return Err(io::Error::from(io::ErrorKind::Other))
+                    warn!(ip = %ip, "Wrong signature");
+                    return Err(MimirError::InvalidData("wrong signature".to_owned()))
+                }
+                let hook_ip = Ipv6Addr::from(ip);
+                // Registered address is checked, not the sender, as it is the one given out to others
+                let max_priority = match self.trusted_subnets.iter().any(|subnet| subnet.contains(hook_ip.into())) {
+                    true => Priority::MAX,
+                    false => self.max_priority_from_public_ips
+                };
+                // Lowered priority would not match the signature anymore, others could not check the address
+                if full_signature && priority > max_priority {
+                    warn!(ip = %hook_ip, "Signed priority {} is above {}, registration rejected", priority, max_priority);
+                    return Ok(write_error(response, nonce, ErrorCode::RegistrationRejected, &[])?)
+                }
+                let priority = priority.min(max_priority);
+                if let Some(e) = self.registration_hooks.iter().find_map(|hook| hook.check(&id, &hook_ip, port, priority, client).err()) {
+                    warn!(ip = %hook_ip, "Registration rejected: {}", e);
+                    return Ok(write_error(response, nonce, ErrorCode::RegistrationRejected, &[])?)
+                }
+                // Counted only after the signature, others can't fill the quota of an ID
+                let addr_flags = if full_signature { ADDR_FLAG_FULL_SIGNATURE } else { 0 };
+                let registration = Registration { ip, signature, signed_at: request_timestamp, port, priority, client, latency_hint_ms, flags: addr_flags };
+                let result = storage.register_or_skip(&id, &registration, DEFAULT_TTL, self.max_addresses_per_id);
+                if result.action == RegistrationAction::LimitReached {
+                    warn!(ip = %hook_ip, "Too many addresses for ID, registration rejected");
+                    return Ok(write_error(response, nonce, ErrorCode::RateLimited, &[])?)
+                }
+                let stored_ttl = result.ttl;
+                if let Some(metrics) = &self.connection_metrics {
+                    metrics.record_registration(src.ip());
                 }
-                let ttl = storage.save_address(&id, &ip, &signature, port, priority, client);
+                if let Some(metrics) = &self.metrics {
+                    metrics.registrations.fetch_add(1, Ordering::Relaxed);
+                }
+                if let Some(notifier) = &self.notifier {
+                    match register_flags & REGISTER_FLAG_NOTIFY_ON_LOOKUP != 0 && to_ipv6(src.ip()) == hook_ip {
+                        true => notifier.register(&id, client, hook_ip, port, stored_ttl),
+                        false => notifier.unregister(&id, client)
+                    }
+                }
+                let ttl = self.response_ttl.unwrap_or(stored_ttl).min(stored_ttl);
                 let mut w = Cursor::new(response);
                 w.write_u32::<BigEndian>(nonce)?;
-                w.write_u8(command)?;
+                w.write_u8(command.byte())?;
                 w.write_u64::<BigEndian>(ttl)?;
                 return Ok(w.position() as usize);
             }
-            1 => {
-                let results = storage.get_addresses(&id);
+            Command::Lookup => {
+                if let Some(metrics) = &self.metrics {
+                    metrics.queries.fetch_add(1, Ordering::Relaxed);
+                }
+                // Older clients don't send max_results and get all addresses
+                let max_results = if c.remaining() > 0 {
+                    match c.read_u8()? {
+                        0 => Some(DEFAULT_MAX_RESULTS),
+                        max_results => Some(max_results)
+                    }
+                } else {
+                    None
+                };
+                let flags = if c.remaining() > 0 { c.read_u8()? } else { 0 };
+                let mut client_ip = None;
+                let mut querier_ip = None;
+                let mut results = if flags & (LOOKUP_FLAG_CLIENT | LOOKUP_FLAG_MAX_AGE | LOOKUP_FLAG_CLIENT_IP | LOOKUP_FLAG_PRIORITY) != 0 {
+                    let mut filter = AddressFilter { max_results, ..Default::default() };
+                    if flags & LOOKUP_FLAG_CLIENT != 0 {
+                        filter.client = Some(c.read_u32_be()?);
+                    }
+                    if flags & LOOKUP_FLAG_MAX_AGE != 0 {
+                        filter.max_age_secs = c.read_u32_be()? as u64;
+                    }
+                    if flags & LOOKUP_FLAG_CLIENT_IP != 0 {
+                        let ip: [u8; 16] = c.read_array()?;
+                        querier_ip = Some(ip);
+                        if self.local_subnet_boost {
+                            client_ip = Some(ip);
+                            // Addresses from the subnet can have any priority, they are cut after sorting
+                            filter.max_results = None;
+                        }
+                    }
+                    if flags & LOOKUP_FLAG_PRIORITY != 0 {
+                        filter.priority_range = Some((c.read_u8()?, c.read_u8()?));
+                    }
+                    storage.find_addresses(&id, &filter)
+                } else {
+                    match max_results {
+                        Some(max_results) => storage.get_addresses_filtered(&id, max_results),
+                        None => storage.get_addresses(&id)
+                    }
+                };
+                let querier_id: Option<[u8; 32]> = match flags & LOOKUP_FLAG_QUERIER_ID != 0 {
+                    true => Some(c.read_array()?),
+                    false => None
+                };
+                if results.is_empty() {
+                    if let Some(federation) = &self.federation {
+                        if federation.has_peers() && !federation.is_peer(&src.ip()) {
+                            results = federation.lookup(&id);
+                            results.truncate(max_results.unwrap_or(DEFAULT_MAX_RESULTS) as usize);
+                        }
+                    }
+                }
+                if let Some(client_ip) = client_ip {
+                    // Stable sort keeps priority order in both groups
+                    results.sort_by_key(|addr| addr.ip.get(..SUBNET_PREFIX_LEN) != Some(&client_ip[..SUBNET_PREFIX_LEN]));
+                    results.truncate(max_results.unwrap_or(DEFAULT_MAX_RESULTS) as usize);
+                }
+                // Whatever path they came from, the answer has count u8 and has to fit in the buffer
+                results.truncate(self.max_lookup_results(version));
+                if let Some(notifier) = &self.notifier {
+                    let querier_ip = querier_ip.unwrap_or_else(|| to_ipv6(src.ip()).octets());
+                    notifier.notify(socket, &id, &results, &querier_id.unwrap_or([0u8; 32]), &querier_ip);
+                }
                 let mut w = Cursor::new(response);
                 w.write_u32::<BigEndian>(nonce)?;
-                w.write_u8(command)?;
+                w.write_u8(command.byte())?;
                 w.write_u8(results.len() as u8)?;
-                println!("Got {} ips for {:?}", results.len(), &hex);
+                debug!("Got {} ips", results.len());
                 for addr in results.iter() {
-                    w.write_all(addr.ip.as_slice())?;
-                    w.write_all(addr.signature.as_slice())?;
-                    w.write_u16::<BigEndian>(addr.port)?;
-                    w.write_u8(addr.priority)?;
-                    w.write_u32::<BigEndian>(addr.client)?;
-                    w.write_u64::<BigEndian>(addr.ttl)?;
+                    write_addr(&mut w, addr, version)?;
+                }
+                if let Some(keypair) = &self.response_key {
+                    let size = w.position() as usize;
+                    let signature = keypair.sign(&w.get_ref()[..size]);
+                    w.write_all(&signature.to_bytes())?;
+                }
+                return Ok(w.position() as usize);
+            }
+            Command::Deregister => {
+                if version < DEREGISTRATION_VERSION {
+                    warn!("Deregistration without request timestamp");
+                    return Ok(write_error(response, nonce, ErrorCode::UnsupportedVersion, &[self.max_protocol_version])?)
+                }
+                let port = c.read_u16_be()?;
+                let priority = c.read_u8()?;
+                let client = c.read_u32_be()?;
+                let ip: [u8; 16] = c.read_array()?;
+                let signature: [u8; 64] = c.read_array()?;
+                let flags = if c.remaining() > 0 { c.read_u8()? } else { 0 };
+                // Not signed like registrations, their signatures are given out in lookups
+                let signed_data = match version >= REGISTRATION_SIGNATURE_VERSION {
+                    true => full_deregistration_signed_data(&id, &ip, port, priority, client, flags, request_timestamp),
+                    false => deregistration_signed_data(&id, &ip, client, request_timestamp)
+                };
+                if !check_signature(&id, &signature, &signed_data) {
+                    let ip = Ipv6Addr::from(ip);
+                    warn!(ip = %ip, "Wrong signature");
+                    return Err(MimirError::InvalidData("wrong signature".to_owned()))
+                }
+                if flags & FLAG_TOMBSTONE != 0 {
+                    let deleted_at = c.read_u64_be()?;
+                    let tombstone_signature: [u8; 64] = c.read_array()?;
+                    let tombstone = Tombstone { id: id.to_vec(), ip: ip.to_vec(), deleted_at, signature: tombstone_signature.to_vec() };
+                    if deleted_at > get_utc_time() + self.max_time_skew || !check_signature(&id, &tombstone_signature, &tombstone.signed_data()) {
+                        warn!("Wrong tombstone");
+                        return Err(MimirError::InvalidData("wrong tombstone".to_owned()))
+                    }
+                    storage.add_tombstone(&tombstone);
+                }
+                if let Some(notifier) = &self.notifier {
+                    notifier.unregister(&id, client);
+                }
+                // Clients get 0 after hard delete, or how long the address is still given out
+                let ttl = if flags & (FLAG_HARD_DELETE | FLAG_TOMBSTONE) != 0 {
+                    storage.remove_address(&id, &ip, client);
+                    0
+                } else {
+                    let registration = Registration { ip, signature, signed_at: request_timestamp, port, priority, client, latency_hint_ms: 0, flags: 0 };
+                    storage.save_address(&id, &registration, true)
+                };
+                let mut w = Cursor::new(response);
+                w.write_u32::<BigEndian>(nonce)?;
+                w.write_u8(command.byte())?;
+                w.write_u64::<BigEndian>(ttl)?;
+                return Ok(w.position() as usize);
+            }
+            // Answer has `flags` u8 and `count` u8 of IDs, then for every ID in the order of the request
+            // its number of addresses u8 and addresses like in command-1 answers
+            Command::BatchLookup => {
+                if let Some(metrics) = &self.metrics {
+                    metrics.queries.fetch_add(1, Ordering::Relaxed);
+                }
+                let count = c.read_u8()? as usize;
+                if count == 0 || count > MAX_BATCH_IDS {
+                    return Err(MimirError::InvalidData(format!("batch of {} IDs", count)))
+                }
+                let mut ids = Vec::with_capacity(count);
+                for _ in 0..count {
+                    ids.push(c.read_array::<32>()?);
+                }
+                let found = storage.get_peer_addresses(&ids);
+                // Signature of signed answers has to fit too
+                let limit = RESPONSE_BUFFER_SIZE - if self.response_key.is_some() { 64 } else { 0 };
+                let header_size = 7;
+                let mut body = Vec::new();
+                let mut groups = 0u8;
+                let mut truncated = false;
+                'ids: for id in ids.iter() {
+                    if header_size + body.len() >= limit {
+                        truncated = true;
+                        break;
+                    }
+                    let mut addrs = dedup_addrs(found.get(id).cloned().unwrap_or_default());
+                    addrs.truncate(DEFAULT_MAX_RESULTS as usize);
+                    let count_pos = body.len();
+                    body.push(0u8);
+                    groups += 1;
+                    for addr in addrs.iter() {
+                        let mut bytes = Vec::new();
+                        write_addr(&mut bytes, addr, version)?;
+                        if header_size + body.len() + bytes.len() > limit {
+                            truncated = true;
+                            break 'ids;
+                        }
+                        body.extend_from_slice(&bytes);
+                        body[count_pos] += 1;
+                    }
+                }
+                debug!("Got batch of {} IDs, answered {}{}", count, groups, if truncated { ", truncated" } else { "" });
+                let mut w = Cursor::new(response);
+                w.write_u32::<BigEndian>(nonce)?;
+                w.write_u8(command.byte())?;
+                w.write_u8(if truncated { BATCH_FLAG_TRUNCATED } else { 0 })?;
+                w.write_u8(groups)?;
+                w.write_all(&body)?;
+                if let Some(keypair) = &self.response_key {
+                    let size = w.position() as usize;
+                    let signature = keypair.sign(&w.get_ref()[..size]);
+                    w.write_all(&signature.to_bytes())?;
+                }
+                return Ok(w.position() as usize);
+            }
+            // ID of ping requests is ignored, the answer has max protocol version, length of crate version and its bytes
+            Command::Ping => {
+                let crate_version = Version::current().crate_version.as_bytes();
+                let mut w = Cursor::new(response);
+                w.write_u32::<BigEndian>(nonce)?;
+                w.write_u8(command.byte())?;
+                w.write_u8(self.max_protocol_version)?;
+                w.write_u8(crate_version.len() as u8)?;
+                w.write_all(crate_version)?;
+                if let Some(keypair) = &self.response_key {
+                    w.write_all(keypair.public.as_bytes())?;
                 }
                 return Ok(w.position() as usize);
             }
             _ => {
-                println!("Wrong command from {}", src.ip());
+                warn!("Wrong command");
             }
         }
-        Err(io::Error::from(io::ErrorKind::Other))
+        Err(MimirError::InvalidData(format!("unknown command {}", command.byte())))
+    }
+}
+
+/// Binds `workers` sockets to the same address, or one socket for all of them if the system can't do it
+fn bind_sockets(addr: &str, workers: usize) -> Result<Vec<UdpSocket>, io::Error> {
+    if workers > 1 && cfg!(target_os = "linux") {
+        let addr = addr.to_socket_addrs()?.next().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address"))?;
+        return (0..workers).map(|_| bind_reuse_port(addr)).collect();
+    }
+    let socket = UdpSocket::bind(addr)?;
+    let mut sockets = Vec::with_capacity(workers);
+    for _ in 1..workers {
+        sockets.push(socket.try_clone()?);
+    }
+    sockets.push(socket);
+    Ok(sockets)
+}
+
+#[cfg(target_os = "linux")]
+fn bind_reuse_port(addr: SocketAddr) -> Result<UdpSocket, io::Error> {
+    use std::mem::size_of;
+    use std::os::unix::io::FromRawFd;
+    let domain = if addr.is_ipv4() { libc::AF_INET } else { libc::AF_INET6 };
+    let fd = unsafe { libc::socket(domain, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Owned right away, so that the descriptor is closed on errors
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+    let enable: libc::c_int = 1;
+    let result = unsafe {
+        libc::setsockopt(fd, libc::SOL_SOCKET, libc::SO_REUSEPORT, &enable as *const libc::c_int as *const libc::c_void, size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let result = match addr {
+        SocketAddr::V4(addr) => {
+            let sockaddr = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: addr.port().to_be(),
+                sin_addr: libc::in_addr { s_addr: u32::from(*addr.ip()).to_be() },
+                sin_zero: [0; 8]
+            };
+            unsafe { libc::bind(fd, &sockaddr as *const libc::sockaddr_in as *const libc::sockaddr, size_of::<libc::sockaddr_in>() as libc::socklen_t) }
+        }
+        SocketAddr::V6(addr) => {
+            let sockaddr = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_port: addr.port().to_be(),
+                sin6_flowinfo: addr.flowinfo(),
+                sin6_addr: libc::in6_addr { s6_addr: addr.ip().octets() },
+                sin6_scope_id: addr.scope_id()
+            };
+            unsafe { libc::bind(fd, &sockaddr as *const libc::sockaddr_in6 as *const libc::sockaddr, size_of::<libc::sockaddr_in6>() as libc::socklen_t) }
+        }
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(socket)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_reuse_port(_addr: SocketAddr) -> Result<UdpSocket, io::Error> {
+    unreachable!("SO_REUSEPORT sockets are used only on Linux")
+}
+
+#[cfg(target_os = "linux")]
+fn bind_to_device(socket: &UdpSocket, device: &str) -> Result<(), io::Error> {
+    use std::os::unix::io::AsRawFd;
+    // SO_BINDTODEVICE needs CAP_NET_RAW
+    let result = unsafe {
+        libc::setsockopt(socket.as_raw_fd(), libc::SOL_SOCKET, libc::SO_BINDTODEVICE, device.as_ptr() as *const libc::c_void, device.len() as libc::socklen_t)
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_to_device(_socket: &UdpSocket, device: &str) -> Result<(), io::Error> {
+    warn!("Binding to network interface is supported only on Linux, {} is ignored", device);
+    Ok(())
+}
+
+/// Removes expired records every `interval`, `SqliteStorage` does it in batches between requests
+fn cleanup_periodically(storage: &dyn Storage, metrics: Option<&TrackerMetrics>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        let start = Instant::now();
+        let removed = storage.cleanup_expired();
+        info!("Removed {} expired addresses in {:?}", removed, start.elapsed());
+        if let Some(metrics) = metrics {
+            metrics.active_addresses.store(storage.count_total().0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Loads the key pair that signs responses, or generates and saves one on the first start
+fn load_or_create_keypair(storage: &dyn Storage) -> Keypair {
+    if let Some(secret) = storage.get_setting(TRACKER_KEY_SETTING).and_then(|bytes| SecretKey::from_bytes(&bytes).ok()) {
+        let public = PublicKey::from(&secret);
+        return Keypair { secret, public };
+    }
+    let keypair = Keypair::generate(&mut OsRng);
+    if let Err(e) = storage.set_setting(TRACKER_KEY_SETTING, keypair.secret.as_bytes()) {
+        error!("Error saving tracker key, it will change on restart: {}", e);
+    }
+    keypair
+}
+
+/// Writes error answer with `payload` of this `code`, returns its size
+fn write_error(response: &mut [u8], nonce: u32, code: ErrorCode, payload: &[u8]) -> Result<usize, io::Error> {
+    let mut w = Cursor::new(response);
+    w.write_u32::<BigEndian>(nonce)?;
+    w.write_u8(CMD_ERROR)?;
+    w.write_u8(code as u8)?;
+    w.write_all(payload)?;
+    Ok(w.position() as usize)
+}
+
+/// Size of an address written by `write_addr` for this protocol version
+fn addr_size(version: u8) -> usize {
+    let mut size = 16 + 64 + 2 + 1 + 4 + 8;
+    if version >= LATENCY_HINT_VERSION {
+        size += 2;
+    }
+    if version >= ADDR_FLAGS_VERSION {
+        size += 1;
+    }
+    if version >= REQUEST_TIMESTAMP_VERSION {
+        size += 4;
+    }
+    size
+}
+
+/// Writes address in the layout of given protocol version
+fn write_addr<W: Write>(w: &mut W, addr: &Addr, version: u8) -> Result<(), io::Error> {
+    w.write_all(addr.ip.as_slice())?;
+    w.write_all(addr.signature.as_slice())?;
+    w.write_u16::<BigEndian>(addr.port)?;
+    w.write_u8(addr.priority)?;
+    w.write_u32::<BigEndian>(addr.client)?;
+    w.write_u64::<BigEndian>(addr.ttl)?;
+    if version >= LATENCY_HINT_VERSION {
+        w.write_u16::<BigEndian>(addr.latency_hint_ms)?;
+    }
+    if version >= ADDR_FLAGS_VERSION {
+        w.write_u8(addr.flags)?;
+    }
+    if version >= REQUEST_TIMESTAMP_VERSION {
+        w.write_u32::<BigEndian>(addr.signed_at)?;
+    }
+    Ok(())
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip
     }
 }
 
-/// Convert bytes array to HEX format
-pub fn to_hex(buf: &[u8]) -> String {
-    let mut result = String::new();
-    for x in buf.iter() {
-        result.push_str(&format!("{:01$X}", x, 2));
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::StorageBackend;
+    use crate::storage::{ADDR_FLAG_GOING_OFFLINE, IN_MEMORY_DB_PATH, SOFT_DELETE_TTL};
+    use crate::test_helpers::{generate_keypairs, sign_deregistration, sign_full_deregistration, sign_ip, sign_registration};
+
+    const IP: [u8; 16] = [2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+    const NONCE: u32 = 42;
+
+    fn addr() -> Addr {
+        Addr { ip: vec![1; 16], signature: vec![2; 64], port: 5000, priority: 1, client: 7, ttl: 600, latency_hint_ms: 20, flags: 0, signed_at: 0 }
+    }
+
+    /// Request header of this version, `timestamp` is sent only from `REQUEST_TIMESTAMP_VERSION`
+    fn request(version: u8, timestamp: u32, command: Command, id: &[u8; 32], payload: &[u8]) -> Vec<u8> {
+        let mut data = vec![version];
+        data.extend_from_slice(&NONCE.to_be_bytes());
+        if version >= REQUEST_TIMESTAMP_VERSION {
+            data.extend_from_slice(&timestamp.to_be_bytes());
+        }
+        data.push(command.byte());
+        data.extend_from_slice(id);
+        data.extend_from_slice(payload);
+        data
+    }
+
+    /// Payload of commands 0 and 2: port, priority, client, ip and signature
+    fn address_payload(port: u16, priority: Priority, client: u32, ip: [u8; 16], signature: &[u8; 64]) -> Vec<u8> {
+        let mut payload = port.to_be_bytes().to_vec();
+        payload.push(priority);
+        payload.extend_from_slice(&client.to_be_bytes());
+        payload.extend_from_slice(&ip);
+        payload.extend_from_slice(signature);
+        payload
+    }
+
+    fn process(server: &Server, storage: &dyn Storage, data: &[u8]) -> Result<Vec<u8>, MimirError> {
+        let socket = UdpSocket::bind("[::1]:0").unwrap();
+        let mut response = [0u8; RESPONSE_BUFFER_SIZE];
+        let src = "[::1]:5000".parse().unwrap();
+        server.process_message(storage, &socket, data, &mut response, src).map(|size| response[..size].to_vec())
+    }
+
+    /// Saves an address signed like v2 clients do, its signature is given out in lookups
+    fn save_registered(storage: &dyn Storage, key: &Keypair, id: &[u8; 32], signed_at: u32) -> [u8; 64] {
+        let signature = sign_ip(key, IP, signed_at);
+        let registration = Registration { ip: IP, signature, signed_at, port: 5050, priority: 1, client: 7, latency_hint_ms: 0, flags: 0 };
+        storage.save_address(id, &registration, false);
+        signature
+    }
+
+    fn answer_ttl(answer: &[u8], command: Command) -> u64 {
+        assert_eq!(answer[4], command.byte());
+        u64::from_be_bytes(answer[5..13].try_into().unwrap())
+    }
+
+    #[test]
+    fn ping_answer_has_versions() {
+        let storage = SqliteStorage::new_in_memory();
+        let data = request(3, get_utc_time() as u32, Command::Ping, &[0; 32], &[]);
+        let crate_version = env!("CARGO_PKG_VERSION").as_bytes();
+        let mut expected = NONCE.to_be_bytes().to_vec();
+        expected.extend_from_slice(&[Command::Ping.byte(), PROTOCOL_VERSION, crate_version.len() as u8]);
+        expected.extend_from_slice(crate_version);
+        assert_eq!(process(&Server::new("[::1]:0"), &storage, &data).unwrap(), expected);
+
+        let keypair = generate_keypairs(1).remove(0).0;
+        let public = keypair.public.to_bytes();
+        let mut server = Server::new("[::1]:0");
+        server.response_key = Some(Arc::new(keypair));
+        let signed = process(&server, &storage, &data).unwrap();
+        assert_eq!(signed[..expected.len()], expected);
+        assert_eq!(signed[expected.len()..], public);
     }
-    result
-}
\ No newline at end of file
+
+    #[test]
+    fn replayed_nonce_is_dropped() {
+        let mut server = Server::new("[::1]:0");
+        server.nonce_cache = Some(Arc::new(NonceCache::new()));
+        let storage = SqliteStorage::new_in_memory();
+        let data = request(2, get_utc_time() as u32, Command::Ping, &[0; 32], &[]);
+        assert!(process(&server, &storage, &data).is_ok());
+        assert!(process(&server, &storage, &data).is_err());
+    }
+
+    #[test]
+    fn registration_over_address_limit_is_rate_limited() {
+        let server = Server::new("[::1]:0").with_max_addresses_per_id(Some(1));
+        let storage = SqliteStorage::new_in_memory();
+        let (key, id) = &generate_keypairs(1)[0];
+        let now = get_utc_time() as u32;
+        for (client, code) in [(7, None), (8, Some(ErrorCode::RateLimited as u8))] {
+            let signature = sign_registration(key, IP, 5050, 1, client, now);
+            let answer = process(&server, &storage, &request(3, now, Command::Register, id, &address_payload(5050, 1, client, IP, &signature))).unwrap();
+            match code {
+                Some(code) => assert_eq!(answer[4..6], [CMD_ERROR, code]),
+                None => assert_eq!(answer[4], Command::Register.byte())
+            }
+        }
+        assert_eq!(storage.get_addresses_for_client(id, 8), Vec::new());
+    }
+
+    /// Saves `count` addresses of this ID under different clients
+    fn save_addresses(storage: &dyn Storage, key: &Keypair, id: &[u8; 32], count: u32) {
+        for client in 0..count {
+            let registration = Registration { ip: IP, signature: sign_ip(key, IP, 0), signed_at: 0, port: 5050 + client as u16, priority: 1, client, latency_hint_ms: 0, flags: 0 };
+            storage.save_address(id, &registration, false);
+        }
+    }
+
+    fn batch_payload(ids: &[[u8; 32]]) -> Vec<u8> {
+        let mut payload = vec![ids.len() as u8];
+        ids.iter().for_each(|id| payload.extend_from_slice(id));
+        payload
+    }
+
+    #[test]
+    fn batch_lookup_answers_ids_in_order() {
+        let (server, storage) = (Server::new("[::1]:0"), SqliteStorage::new_in_memory());
+        let keys = generate_keypairs(3);
+        save_addresses(&storage, &keys[0].0, &keys[0].1, 2);
+        save_addresses(&storage, &keys[2].0, &keys[2].1, 1);
+        let ids: Vec<_> = keys.iter().map(|(_, id)| *id).collect();
+        let now = get_utc_time() as u32;
+        let answer = process(&server, &storage, &request(3, now, Command::BatchLookup, &[0; 32], &batch_payload(&ids))).unwrap();
+        let size = addr_size(3);
+        assert_eq!(answer[4..7], [Command::BatchLookup.byte(), 0, 3]);
+        assert_eq!(answer[7], 2);
+        assert_eq!(answer[8 + 2 * size], 0);
+        assert_eq!(answer[9 + 2 * size], 1);
+        assert_eq!(answer.len(), 10 + 3 * size);
+    }
+
+    #[test]
+    fn batch_lookup_is_truncated_to_response_size() {
+        let (server, storage) = (Server::new("[::1]:0"), SqliteStorage::new_in_memory());
+        let keys = generate_keypairs(MAX_BATCH_IDS);
+        for (key, id) in keys.iter() {
+            save_addresses(&storage, key, id, DEFAULT_MAX_RESULTS as u32);
+        }
+        let ids: Vec<_> = keys.iter().map(|(_, id)| *id).collect();
+        let now = get_utc_time() as u32;
+        let answer = process(&server, &storage, &request(3, now, Command::BatchLookup, &[0; 32], &batch_payload(&ids))).unwrap();
+        assert_eq!(answer[5], BATCH_FLAG_TRUNCATED);
+        assert!(answer.len() <= RESPONSE_BUFFER_SIZE);
+        assert!((answer[6] as usize) < MAX_BATCH_IDS);
+    }
+
+    #[test]
+    fn batch_lookup_needs_1_to_16_ids() {
+        let (server, storage) = (Server::new("[::1]:0"), SqliteStorage::new_in_memory());
+        let now = get_utc_time() as u32;
+        assert!(process(&server, &storage, &request(3, now, Command::BatchLookup, &[0; 32], &[0])).is_err());
+        let ids = vec![[1u8; 32]; MAX_BATCH_IDS + 1];
+        assert!(process(&server, &storage, &request(3, now, Command::BatchLookup, &[0; 32], &batch_payload(&ids))).is_err());
+    }
+
+    /// Registers `IP` with a v3 signature of all fields
+    fn register(server: &Server, storage: &dyn Storage, key: &Keypair, id: &[u8; 32], priority: Priority) -> Result<Vec<u8>, MimirError> {
+        let now = get_utc_time() as u32;
+        let signature = sign_registration(key, IP, 5050, priority, 7, now);
+        process(server, storage, &request(3, now, Command::Register, id, &address_payload(5050, priority, 7, IP, &signature)))
+    }
+
+    #[test]
+    fn response_ttl_is_capped_by_stored_ttl() {
+        let storage = SqliteStorage::new_in_memory();
+        let keys = generate_keypairs(4);
+        for ((key, id), (response_ttl, expected)) in keys.iter().zip([(Some(UPDATE_TTL), UPDATE_TTL), (Some(300), 300), (None, DEFAULT_TTL), (Some(DEFAULT_TTL * 2), DEFAULT_TTL)]) {
+            let server = Server::new("[::1]:0").with_response_ttl(response_ttl);
+            let answer = register(&server, &storage, key, id, 1).unwrap();
+            assert_eq!(answer_ttl(&answer, Command::Register), expected, "response TTL {:?}", response_ttl);
+            assert!(storage.get_addresses(id)[0].ttl > DEFAULT_TTL - 10);
+        }
+    }
+
+    #[test]
+    fn in_memory_storage_registers_and_looks_up() {
+        let server = Server::new("[::1]:0").with_db_path(IN_MEMORY_DB_PATH);
+        let storage = SqliteStorage::new(&server.db_path);
+        let (key, id) = &generate_keypairs(1)[0];
+        register(&server, &storage, key, id, 1).unwrap();
+        let answer = process(&server, &storage, &request(3, get_utc_time() as u32, Command::Lookup, id, &[])).unwrap();
+        assert_eq!(answer[4..6], [Command::Lookup.byte(), 1]);
+        assert_eq!(answer[6..22], IP);
+        assert!(!std::path::Path::new(IN_MEMORY_DB_PATH).exists());
+    }
+
+    #[test]
+    fn listens_on_multiple_addresses_with_shared_storage() {
+        // Ports that were free a moment ago, the server binds them again
+        let addresses: Vec<String> = (0..2).map(|_| UdpSocket::bind("[::1]:0").unwrap().local_addr().unwrap().to_string()).collect();
+        let server = Server::new_with_storage("[::1]:0", SqliteStorage::new_in_memory()).with_cleanup_interval(None);
+        let handles = server.listen_on_multiple(addresses.clone());
+        assert_eq!(handles.len(), 2);
+
+        let client = UdpSocket::bind("[::1]:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let exchange = |address: &str, data: &[u8]| {
+            client.send_to(data, address).unwrap();
+            let mut answer = [0u8; RESPONSE_BUFFER_SIZE];
+            let (size, _) = client.recv_from(&mut answer).unwrap();
+            answer[..size].to_vec()
+        };
+        let (key, id) = &generate_keypairs(1)[0];
+        let now = get_utc_time() as u32;
+        let signature = sign_registration(key, IP, 5050, 1, 7, now);
+        let answer = exchange(&addresses[0], &request(3, now, Command::Register, id, &address_payload(5050, 1, 7, IP, &signature)));
+        assert_eq!(answer[4], Command::Register.byte());
+        // Registered through the first address, found through the second one
+        let answer = exchange(&addresses[1], &request(3, now, Command::Lookup, id, &[]));
+        assert_eq!(answer[4..6], [Command::Lookup.byte(), 1]);
+    }
+
+    fn memory_storage() -> Box<dyn Storage> {
+        StorageBackend::Memory.open("").unwrap()
+    }
+
+    #[test]
+    fn register_with_valid_signature_saves_address() {
+        let (server, storage) = (Server::new("[::1]:0"), memory_storage());
+        let (key, id) = &generate_keypairs(1)[0];
+        let answer = register(&server, storage.as_ref(), key, id, 1).unwrap();
+        assert_eq!(answer[..4], NONCE.to_be_bytes());
+        assert_eq!(answer_ttl(&answer, Command::Register), UPDATE_TTL);
+        let addrs = storage.get_addresses(id);
+        assert_eq!(addrs.len(), 1);
+        assert_eq!((addrs[0].ip.as_slice(), addrs[0].port, addrs[0].client), (IP.as_slice(), 5050, 7));
+        assert_eq!(addrs[0].flags, ADDR_FLAG_FULL_SIGNATURE);
+    }
+
+    #[test]
+    fn register_with_invalid_signature_is_refused() {
+        let (server, storage) = (Server::new("[::1]:0"), memory_storage());
+        let (key, id) = &generate_keypairs(1)[0];
+        let now = get_utc_time() as u32;
+        // Signed priority 1, sent priority 2
+        let signature = sign_registration(key, IP, 5050, 1, 7, now);
+        let data = request(3, now, Command::Register, id, &address_payload(5050, 2, 7, IP, &signature));
+        assert!(process(&server, storage.as_ref(), &data).is_err());
+        assert!(storage.get_addresses(id).is_empty());
+    }
+
+    #[test]
+    fn lookup_of_unknown_id_has_no_results() {
+        let (server, storage) = (Server::new("[::1]:0"), memory_storage());
+        let answer = process(&server, storage.as_ref(), &request(3, get_utc_time() as u32, Command::Lookup, &[1; 32], &[])).unwrap();
+        assert_eq!(answer, [&NONCE.to_be_bytes()[..], &[Command::Lookup.byte(), 0]].concat());
+    }
+
+    #[test]
+    fn lookup_answers_all_fields() {
+        let (server, storage) = (Server::new("[::1]:0"), memory_storage());
+        let (key, id) = &generate_keypairs(1)[0];
+        let now = get_utc_time() as u32;
+        for (client, priority) in [(7, 1), (8, 2)] {
+            let ip = [client as u8; 16];
+            let signature = sign_registration(key, ip, 5050, priority, client, now);
+            let mut payload = address_payload(5050, priority, client, ip, &signature);
+            payload.extend_from_slice(&25u16.to_be_bytes());
+            process(&server, storage.as_ref(), &request(3, now, Command::Register, id, &payload)).unwrap();
+        }
+        let answer = process(&server, storage.as_ref(), &request(3, now, Command::Lookup, id, &[DEFAULT_MAX_RESULTS])).unwrap();
+        assert_eq!(answer[4..6], [Command::Lookup.byte(), 2]);
+        assert_eq!(answer.len(), 6 + 2 * addr_size(3));
+        // Higher priority first with max_results
+        for (addr, (client, priority)) in answer[6..].chunks(addr_size(3)).zip([(8u32, 2u8), (7, 1)]) {
+            let ip = [client as u8; 16];
+            assert_eq!(addr[..16], ip);
+            assert_eq!(addr[16..80], sign_registration(key, ip, 5050, priority, client, now));
+            assert_eq!(addr[80..82], 5050u16.to_be_bytes());
+            assert_eq!(addr[82], priority);
+            assert_eq!(addr[83..87], client.to_be_bytes());
+            let ttl = u64::from_be_bytes(addr[87..95].try_into().unwrap());
+            assert!(ttl > DEFAULT_TTL - 10 && ttl <= DEFAULT_TTL);
+            assert_eq!(addr[95..97], 25u16.to_be_bytes());
+            assert_eq!(addr[97], ADDR_FLAG_FULL_SIGNATURE);
+            assert_eq!(addr[98..102], now.to_be_bytes());
+        }
+    }
+
+    #[test]
+    fn unknown_command_is_refused() {
+        let (server, storage) = (Server::new("[::1]:0"), memory_storage());
+        let data = request(3, get_utc_time() as u32, Command::Unknown(0x42), &[1; 32], &[]);
+        assert!(process(&server, storage.as_ref(), &data).is_err());
+    }
+
+    #[test]
+    fn priority_from_public_ips_is_capped() {
+        let storage = memory_storage();
+        let keys = generate_keypairs(3);
+        let now = get_utc_time() as u32;
+        // Only ip is signed before v3, the priority is lowered
+        let (key, id) = &keys[0];
+        let data = request(2, now, Command::Register, id, &address_payload(5050, 255, 7, IP, &sign_ip(key, IP, now)));
+        process(&Server::new("[::1]:0"), storage.as_ref(), &data).unwrap();
+        assert_eq!(storage.get_addresses(id)[0].priority, DEFAULT_MAX_PUBLIC_PRIORITY);
+        // Signed priority can't be lowered, the registration is refused
+        let (key, id) = &keys[1];
+        let answer = register(&Server::new("[::1]:0"), storage.as_ref(), key, id, 255).unwrap();
+        assert_eq!(answer[4..6], [CMD_ERROR, ErrorCode::RegistrationRejected as u8]);
+        assert!(storage.get_addresses(id).is_empty());
+        // Addresses of trusted subnets keep any priority
+        let (key, id) = &keys[2];
+        let server = Server::new("[::1]:0").with_trusted_subnet("200::/7".parse().unwrap());
+        register(&server, storage.as_ref(), key, id, 255).unwrap();
+        assert_eq!(storage.get_addresses(id)[0].priority, 255);
+    }
+
+    #[test]
+    fn trusted_ips_are_not_rate_limited() {
+        let storage = memory_storage();
+        let (key, id) = &generate_keypairs(1)[0];
+        let metrics = Arc::new(TrackerMetrics::new());
+        let limited = Server::new("[::1]:0").with_rate_limiter(RateLimiter::new(1)).with_metrics(Arc::clone(&metrics));
+        // Requests of `process` come from ::1
+        let trusted = Server::new("[::1]:0").with_rate_limiter(RateLimiter::new(1)).with_trusted_ip_range("::1/128".parse().unwrap());
+        assert_eq!(register(&limited, storage.as_ref(), key, id, 1).unwrap()[4], Command::Register.byte());
+        assert_eq!(register(&limited, storage.as_ref(), key, id, 1).unwrap()[4..6], [CMD_ERROR, ErrorCode::RateLimited as u8]);
+        assert_eq!(metrics.rate_limited_registrations.load(Ordering::Relaxed), 1);
+        for _ in 0..3 {
+            assert_eq!(register(&trusted, storage.as_ref(), key, id, 1).unwrap()[4], Command::Register.byte());
+        }
+    }
+
+    #[cfg(feature = "statsd")]
+    #[test]
+    fn registration_is_sent_to_statsd() {
+        let stub = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let metrics = Arc::new(TrackerMetrics::new());
+        let statsd = Arc::new(StatsdEmitter::new(&stub.local_addr().unwrap().to_string(), Arc::clone(&metrics)).unwrap());
+        let server = Server::new("[::1]:0").with_metrics(metrics).with_statsd(Arc::clone(&statsd));
+        let storage = memory_storage();
+        let (key, id) = &generate_keypairs(1)[0];
+        register(&server, storage.as_ref(), key, id, 1).unwrap();
+        statsd.flush();
+        let expected = ["mimir.registrations.total:1|c", "mimir.errors.total:0|c"];
+        let lines = crate::statsd::tests::receive_lines(&stub, &expected);
+        assert!(expected.iter().all(|line| lines.contains(&line.to_string())), "{:?}", lines);
+    }
+
+    #[test]
+    fn trusted_ips_are_not_banned() {
+        let ping = request(3, get_utc_time() as u32, Command::Ping, &[0; 32], &[]);
+        for (trusted, answered) in [(false, false), (true, true)] {
+            let address = UdpSocket::bind("[::1]:0").unwrap().local_addr().unwrap().to_string();
+            let mut server = Server::new_with_storage("[::1]:0", SqliteStorage::new_in_memory()).with_cleanup_interval(None).with_ban("::1/128");
+            if trusted {
+                server = server.with_trusted_ip_range("::1/128".parse().unwrap());
+            }
+            server.listen_on_multiple(vec![address.clone()]);
+            let client = UdpSocket::bind("[::1]:0").unwrap();
+            client.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+            client.send_to(&ping, &address).unwrap();
+            assert_eq!(client.recv_from(&mut [0u8; RESPONSE_BUFFER_SIZE]).is_ok(), answered, "trusted {}", trusted);
+        }
+    }
+
+    #[test]
+    fn uptime_counts_from_creation() {
+        let mut server = Server::new("[::1]:0");
+        thread::sleep(Duration::from_millis(1));
+        assert_eq!(server.uptime_secs(), 0);
+        server.started_at -= Duration::from_secs(5);
+        assert_eq!(server.uptime_secs(), 5);
+        // Clones serve the same tracker
+        assert_eq!(server.clone().uptime_secs(), 5);
+    }
+
+    #[test]
+    fn registration_signature_does_not_deregister() {
+        let (server, storage) = (Server::new("[::1]:0"), SqliteStorage::new_in_memory());
+        let (key, id) = &generate_keypairs(1)[0];
+        let now = get_utc_time() as u32;
+        let signature = save_registered(&storage, key, id, now);
+        // Anyone gets this signature with the lookup result
+        let data = request(2, now, Command::Deregister, id, &address_payload(5050, 1, 7, IP, &signature));
+        assert!(process(&server, &storage, &data).is_err());
+        let addrs = storage.get_addresses(id);
+        assert_eq!(addrs.len(), 1);
+        assert_eq!(addrs[0].flags & ADDR_FLAG_GOING_OFFLINE, 0);
+    }
+
+    #[test]
+    fn signed_deregistration_soft_deletes() {
+        let (server, storage) = (Server::new("[::1]:0"), SqliteStorage::new_in_memory());
+        let (key, id) = &generate_keypairs(1)[0];
+        let now = get_utc_time() as u32;
+        save_registered(&storage, key, id, now);
+        let signature = sign_deregistration(key, IP, 7, now);
+        let answer = process(&server, &storage, &request(2, now, Command::Deregister, id, &address_payload(5050, 1, 7, IP, &signature))).unwrap();
+        assert_eq!(answer_ttl(&answer, Command::Deregister), SOFT_DELETE_TTL);
+        assert_eq!(storage.get_addresses(id)[0].flags & ADDR_FLAG_GOING_OFFLINE, ADDR_FLAG_GOING_OFFLINE);
+    }
+
+    #[test]
+    fn v3_deregistration_signs_flags() {
+        let (server, storage) = (Server::new("[::1]:0"), SqliteStorage::new_in_memory());
+        let (key, id) = &generate_keypairs(1)[0];
+        let now = get_utc_time() as u32;
+        save_registered(&storage, key, id, now);
+        // Signed as soft delete, sent as hard delete
+        let signature = sign_full_deregistration(key, IP, 5050, 1, 7, 0, now);
+        let mut payload = address_payload(5050, 1, 7, IP, &signature);
+        payload.push(FLAG_HARD_DELETE);
+        assert!(process(&server, &storage, &request(3, now, Command::Deregister, id, &payload)).is_err());
+        assert_eq!(storage.get_addresses(id).len(), 1);
+
+        let signature = sign_full_deregistration(key, IP, 5050, 1, 7, FLAG_HARD_DELETE, now);
+        let mut payload = address_payload(5050, 1, 7, IP, &signature);
+        payload.push(FLAG_HARD_DELETE);
+        let answer = process(&server, &storage, &request(3, now, Command::Deregister, id, &payload)).unwrap();
+        assert_eq!(answer_ttl(&answer, Command::Deregister), 0);
+        assert!(storage.get_addresses(id).is_empty());
+    }
+
+    #[test]
+    fn deregistration_needs_request_timestamp() {
+        let (server, storage) = (Server::new("[::1]:0"), SqliteStorage::new_in_memory());
+        let (key, id) = &generate_keypairs(1)[0];
+        let signature = save_registered(&storage, key, id, 0);
+        let answer = process(&server, &storage, &request(1, 0, Command::Deregister, id, &address_payload(5050, 1, 7, IP, &signature))).unwrap();
+        assert_eq!(answer[4..6], [CMD_ERROR, ErrorCode::UnsupportedVersion as u8]);
+        assert_eq!(storage.get_addresses(id).len(), 1);
+    }
+
+    #[test]
+    fn addr_size_matches_written_addr() {
+        for version in 0..=PROTOCOL_VERSION {
+            let mut bytes = Vec::new();
+            write_addr(&mut bytes, &addr(), version).unwrap();
+            assert_eq!(bytes.len(), addr_size(version), "version {}", version);
+        }
+    }
+
+    #[test]
+    fn lookup_results_fit_in_response() {
+        let mut server = Server::new("[::1]:0");
+        for signature_size in [0, 64] {
+            for version in 0..=PROTOCOL_VERSION {
+                let max = server.max_lookup_results(version);
+                assert!(6 + max * addr_size(version) + signature_size <= RESPONSE_BUFFER_SIZE);
+                assert!(6 + (max + 1) * addr_size(version) + signature_size > RESPONSE_BUFFER_SIZE);
+            }
+            server.response_key = generate_keypairs(1).pop().map(|(key, _)| Arc::new(key));
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How long server threads may finish the requests they process after shutdown is requested
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+/// Server loops check the flag at least this often, even if no packets come
+pub const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Static, as signal handlers can't get anything else
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Asks all server loops of this process to stop after the request they process
+pub fn request_shutdown() {
+    SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+pub fn is_shutdown_requested() -> bool {
+    SHUTDOWN_REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Makes SIGTERM and SIGINT request shutdown, a second signal exits at once
+#[cfg(target_os = "linux")]
+pub fn install_signal_handlers() -> Result<(), io::Error> {
+    extern "C" fn handle_signal(signal: libc::c_int) {
+        // Only async-signal-safe calls are allowed here
+        if SHUTDOWN_REQUESTED.swap(true, Ordering::SeqCst) {
+            unsafe { libc::_exit(128 + signal) };
+        }
+    }
+    for signal in [libc::SIGTERM, libc::SIGINT] {
+        if unsafe { libc::signal(signal, handle_signal as *const () as libc::sighandler_t) } == libc::SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Signals keep their default action on other systems, the process is killed without shutdown
+#[cfg(not(target_os = "linux"))]
+pub fn install_signal_handlers() -> Result<(), io::Error> {
+    Ok(())
+}
+
+/// Waits until shutdown is requested, or all `handles` are finished because their threads panicked
+pub fn wait_for_shutdown(handles: &[JoinHandle<()>]) {
+    while !is_shutdown_requested() && !handles.iter().all(|handle| handle.is_finished()) {
+        thread::sleep(SHUTDOWN_POLL_INTERVAL);
+    }
+}
+
+/// Joins all threads if they finish in `timeout`, returns false without joining them otherwise
+pub fn join_with_timeout(handles: Vec<JoinHandle<()>>, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while !handles.iter().all(|handle| handle.is_finished()) {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    for handle in handles {
+        handle.join().expect("Could not join server thread!");
+    }
+    true
+}
@@ -0,0 +1,146 @@
+//! StatsD output of `TrackerMetrics`, for infrastructure that doesn't scrape Prometheus
+use std::collections::HashMap;
+use std::env;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use cadence::{Counted, Gauged, MetricError, QueuingMetricSink, StatsdClient, Timed, UdpMetricSink};
+use tracing::debug;
+use crate::error::MimirError;
+use crate::metrics::TrackerMetrics;
+
+/// UDP address of the StatsD server, like `127.0.0.1:8125`
+pub const STATSD_ADDR_ENV: &str = "MIMIR_STATSD_ADDR";
+pub const DEFAULT_STATSD_ADDR: &str = "127.0.0.1:8125";
+/// Counters are sent this often, as the change since they were sent last time
+pub const DEFAULT_STATSD_INTERVAL: Duration = Duration::from_secs(10);
+/// Timer of `process_message` of every request
+pub const REQUEST_DURATION_METRIC: &str = "mimir.request.duration";
+
+/// Sends metrics to StatsD from its own thread, so that server threads don't wait for the network
+pub struct StatsdEmitter {
+    client: StatsdClient,
+    metrics: Arc<TrackerMetrics>,
+    /// Values of counters sent last time by StatsD name
+    sent: Mutex<HashMap<String, u64>>
+}
+
+impl StatsdEmitter {
+    pub fn new(addr: &str, metrics: Arc<TrackerMetrics>) -> Result<Self, MimirError> {
+        let target = addr.to_socket_addrs()?.next()
+            .ok_or_else(|| MimirError::InvalidData(format!("wrong StatsD address {}", addr)))?;
+        let socket = UdpSocket::bind(if target.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+        socket.set_nonblocking(true)?;
+        let sink = UdpMetricSink::from(target, socket).map_err(to_mimir_error)?;
+        let client = StatsdClient::from_sink("", QueuingMetricSink::from(sink));
+        Ok(StatsdEmitter { client, metrics, sent: Mutex::new(HashMap::new()) })
+    }
+
+    /// Sends to `MIMIR_STATSD_ADDR`, or to `DEFAULT_STATSD_ADDR` if it is not set
+    pub fn from_env(metrics: Arc<TrackerMetrics>) -> Result<Self, MimirError> {
+        let addr = env::var(STATSD_ADDR_ENV).unwrap_or_else(|_| DEFAULT_STATSD_ADDR.to_owned());
+        StatsdEmitter::new(&addr, metrics)
+    }
+
+    pub fn record_request_duration(&self, duration: Duration) {
+        if let Err(e) = self.client.time(REQUEST_DURATION_METRIC, duration) {
+            debug!("Error sending StatsD timer: {}", e);
+        }
+    }
+
+    /// Sends counters as the change since the last flush, and gauges as they are
+    pub fn flush(&self) {
+        let mut sent = self.sent.lock().unwrap();
+        for (name, kind, _, value) in self.metrics.values() {
+            let name = statsd_name(name);
+            let result = match kind {
+                "counter" => {
+                    let last = sent.insert(name.clone(), value).unwrap_or(0);
+                    self.client.count(&name, value.saturating_sub(last)).map(|_| ())
+                }
+                _ => self.client.gauge(&name, value).map(|_| ())
+            };
+            if let Err(e) = result {
+                debug!("Error sending StatsD metric {}: {}", name, e);
+            }
+        }
+    }
+
+    /// Flushes metrics every `interval` until the process ends
+    pub fn start(self: &Arc<Self>, interval: Duration) -> JoinHandle<()> {
+        let emitter = Arc::clone(self);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            emitter.flush();
+        })
+    }
+}
+
+/// Prometheus name in StatsD style, like `mimir.registrations.total` for `mimir_registrations_total`
+fn statsd_name(name: &str) -> String {
+    let name = name.replacen("mimir_", "mimir.", 1);
+    match name.strip_suffix("_total") {
+        Some(base) => format!("{}.total", base),
+        None => name
+    }
+}
+
+fn to_mimir_error(e: MetricError) -> MimirError {
+    MimirError::InvalidData(format!("StatsD error: {}", e))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::atomic::Ordering;
+    use std::time::Instant;
+
+    /// Collects StatsD lines from `socket` until all of `expected` are there or a second passes
+    pub(crate) fn receive_lines(socket: &UdpSocket, expected: &[&str]) -> Vec<String> {
+        socket.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let started = Instant::now();
+        let mut lines = Vec::new();
+        let mut buf = [0u8; 1024];
+        while started.elapsed() < Duration::from_secs(1) && !expected.iter().all(|line| lines.iter().any(|l: &String| l.starts_with(line))) {
+            if let Ok(length) = socket.recv(&mut buf) {
+                lines.extend(String::from_utf8_lossy(&buf[..length]).lines().map(str::to_owned));
+            }
+        }
+        lines
+    }
+
+    #[test]
+    fn names_follow_prometheus_ones() {
+        assert_eq!(statsd_name("mimir_requests_total"), "mimir.requests.total");
+        assert_eq!(statsd_name("mimir_rate_limited_registrations_total"), "mimir.rate_limited_registrations.total");
+        assert_eq!(statsd_name("mimir_active_addresses"), "mimir.active_addresses");
+    }
+
+    #[test]
+    fn counters_are_sent_as_changes() {
+        let stub = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let metrics = Arc::new(TrackerMetrics::new());
+        let emitter = StatsdEmitter::new(&stub.local_addr().unwrap().to_string(), Arc::clone(&metrics)).unwrap();
+        metrics.requests.store(5, Ordering::Relaxed);
+        metrics.active_addresses.store(3, Ordering::Relaxed);
+        emitter.flush();
+        let expected = ["mimir.requests.total:5|c", "mimir.errors.total:0|c", "mimir.active_addresses:3|g"];
+        let lines = receive_lines(&stub, &expected);
+        assert!(expected.iter().all(|line| lines.contains(&line.to_string())), "{:?}", lines);
+
+        metrics.requests.store(7, Ordering::Relaxed);
+        emitter.flush();
+        let lines = receive_lines(&stub, &["mimir.requests.total:2|c"]);
+        assert!(lines.contains(&"mimir.requests.total:2|c".to_owned()), "{:?}", lines);
+    }
+
+    #[test]
+    fn request_duration_is_timer() {
+        let stub = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let emitter = StatsdEmitter::new(&stub.local_addr().unwrap().to_string(), Arc::new(TrackerMetrics::new())).unwrap();
+        emitter.record_request_duration(Duration::from_millis(12));
+        let lines = receive_lines(&stub, &["mimir.request.duration:12|ms"]);
+        assert!(lines.contains(&"mimir.request.duration:12|ms".to_owned()), "{:?}", lines);
+    }
+}
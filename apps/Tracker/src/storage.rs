@@ -1,126 +1,1283 @@
-use sqlite::{Connection, State};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use serde::{Deserialize, Serialize};
+use sqlite::{Connection, State, Statement};
+use tracing::{debug, field, info, info_span, Span};
+use crate::error::MimirError;
+use crate::functions::{check_ip_signature, check_registration_signature, dedup_addrs, format_utc_time, from_hex_array, to_hex};
+use crate::queries::*;
 
-pub trait Storage {
-    /// Saves new or updates old address for this ID, and returns TTL in seconds
-    fn save_address(&self, id: &[u8], ip: &[u8], signature: &[u8], port: u16, priority: u8, client: u32) -> u64;
-    /// Gets all saved addresses
+pub trait Storage: Send + Sync {
+    /// Saves new or updates old address for this ID, and returns TTL in seconds.
+    /// With `soft_delete` the saved address with the ip and client of `registration` is kept for `SOFT_DELETE_TTL`
+    /// marked as going offline, nothing else of it is changed. 0 is returned if there is none.
+    fn save_address(&self, id: &[u8], registration: &Registration, soft_delete: bool) -> u64;
+    /// Saves address with given TTL like `save_address`, but skips the write if the same address is saved and fresh enough.
+    /// Changes of `latency_hint_ms` alone don't cause a write. `flags` are saved with the address, like `ADDR_FLAG_FULL_SIGNATURE`.
+    /// A new address is not saved if the ID has `max_addresses` not expired ones, the result is `LimitReached` then.
+    fn register_or_skip(&self, id: &[u8], registration: &Registration, new_ttl: u64, max_addresses: Option<u64>) -> RegistrationResult;
+    /// Refreshes timestamp and TTL of an existing address, returns new TTL or None if not found
+    fn touch(&self, id: &[u8], ip: &[u8], client: ClientId) -> Option<u64>;
+    /// Gets all saved addresses, except the ones deleted by tombstones, one per `ip` and `port` (see `dedup_addrs`)
     fn get_addresses(&self, id: &[u8]) -> Vec<Addr>;
+    /// Gets not expired addresses of all these IDs in one query, highest priority first.
+    /// IDs without addresses are missing from the result.
+    fn get_peer_addresses(&self, ids: &[[u8; 32]]) -> HashMap<[u8; 32], Vec<Addr>>;
+    /// Gets up to `max_results` saved addresses, highest priority first
+    fn get_addresses_filtered(&self, id: &[u8], max_results: u8) -> Vec<Addr>;
+    /// Gets saved addresses registered by this client type, highest priority first
+    fn get_addresses_for_client(&self, id: &[u8], client: ClientId) -> Vec<Addr>;
+    /// Gets addresses saved less than `max_age_secs` ago, highest priority first
+    fn get_addresses_recent(&self, id: &[u8], max_age_secs: u64) -> Vec<Addr>;
+    /// Gets addresses with priority from `min_priority` to `max_priority` inclusive, highest priority first
+    fn get_addresses_by_priority_range(&self, id: &[u8], min_priority: Priority, max_priority: Priority) -> Vec<Addr>;
+    /// Gets addresses matching all conditions of `filter`, highest priority first
+    fn find_addresses(&self, id: &[u8], filter: &AddressFilter) -> Vec<Addr>;
+    /// Removes the address of this client right away if it is still at `ip`, returns true if it was saved.
+    /// Matching `ip` keeps old signed packets of a previous address from removing the current one.
+    fn remove_address(&self, id: &[u8], ip: &[u8], client: ClientId) -> bool;
+    /// Removes all addresses saved for this ID, returns the number of removed rows
+    fn prune_id(&self, id: &[u8]) -> u64;
+    /// Gets one page of all registered IDs, `page` starts from 0
+    fn get_all_ids(&self, page: u32, page_size: u32) -> Vec<Vec<u8>>;
+    /// Gets up to `limit` IDs ordered after `cursor`, and the cursor of the next page if there can be one.
+    /// Unlike `get_all_ids` every page is found by index, so scanning all IDs page by page stays fast.
+    fn list_ids_paginated(&self, cursor: Option<Vec<u8>>, limit: usize) -> (Vec<Vec<u8>>, Option<Vec<u8>>);
+    /// Counts saved addresses and distinct IDs, returns `(total_rows, distinct_ids)`
+    fn count_total(&self) -> (u64, u64);
+    /// Counts distinct IDs with not expired addresses at this IP, to spot hosts registering many IDs
+    fn count_active_ids_for_ip(&self, ip: &[u8]) -> u64;
+    /// Counts not expired addresses of this ID, to cap them without reading all of them
+    fn count_addresses_for_id(&self, id: &[u8]) -> u64;
+    /// Saves tombstone of a deleted address and removes its older registrations, the signature must be checked before
+    fn add_tombstone(&self, tombstone: &Tombstone) -> bool;
+    /// Gets tombstones deleted at or after `since`, oldest first, to replicate them to peers
+    fn get_tombstones_since(&self, since: u64) -> Vec<Tombstone>;
+    /// Saves ban of an IP or subnet, replacing the saved ban of the same `ip_cidr`
+    fn add_ban(&self, ban: &Ban) -> Result<(), MimirError>;
+    /// Gets the ban of exactly this `ip_cidr` if it is not expired
+    fn get_ban(&self, ip_cidr: &str) -> Option<Ban>;
+    fn get_active_bans(&self) -> Vec<Ban>;
+    /// Saves ID that must not be registered, replacing the reason it was rejected before
+    fn add_rejected_id(&self, id: &[u8], rejected_at: u64, reason: &str) -> Result<(), MimirError>;
+    fn get_rejected_ids(&self) -> Vec<Vec<u8>>;
+    /// Removes all expired addresses, tombstones and bans, returns the number of removed addresses
+    fn cleanup_expired(&self) -> u64;
+    /// Returns free pages of the database file to the system, a few at a time.
+    /// With `full` the whole file is rebuilt, that blocks all other operations until it is done.
+    fn vacuum(&self, full: bool) -> Result<(), MimirError>;
+    /// Writes everything saved before to its final place, called on shutdown after the last request
+    fn flush(&self) -> Result<(), MimirError>;
+    /// Gets a value of the tracker itself, like its key pair, saved with `set_setting`
+    fn get_setting(&self, name: &str) -> Option<Vec<u8>>;
+    fn set_setting(&self, name: &str, value: &[u8]) -> Result<(), MimirError>;
 }
 
-const SQL_CREATE_TABLES: &str = include_str!("create_db.sql");
-const SQL_INSERT_IP: &str = "INSERT INTO clients (id, ip, signature, port, priority, client, timestamp, ttl) VALUES (?, ?, ?, ?, ?, ?, ?, ?)";
-const SQL_UPDATE_IP: &str = "UPDATE clients SET ip=?, signature=?, port=?, priority=?, timestamp=?, ttl=? WHERE id=? AND client=?";
-const SQL_SELECT_IPS: &str = "SELECT ip, signature, port, priority, client, timestamp, ttl FROM clients WHERE id=?";
-
 pub struct SqliteStorage {
-    db: Connection
+    db: Mutex<Connection>
 }
 
+/// SQLite keeps this database in memory only, nothing is written to disk
+pub const IN_MEMORY_DB_PATH: &str = ":memory:";
 const DEFAULT_PORT: u16 = 5050;
-const DEFAULT_TTL: u64 = 3600;
-const UPDATE_TTL: u64 = 600;
+pub const DEFAULT_TTL: u64 = 3600;
+/// How soon clients are asked to re-register
+pub const UPDATE_TTL: u64 = 600;
 const ERROR_TTL: u64 = 120;
+/// How long soft deleted addresses are still given to clients
+pub const SOFT_DELETE_TTL: u64 = 60;
+/// Tombstones are kept for the longest address TTL and an hour more, peers have seen them by then
+pub const TOMBSTONE_TTL: u64 = DEFAULT_TTL + 3600;
+/// Set in `Addr::flags` of soft deleted addresses
+pub const ADDR_FLAG_GOING_OFFLINE: u8 = 0x80;
+/// Set in `Addr::flags` when `signature` covers all fields of `registration_signed_data`, not only `ip`
+pub const ADDR_FLAG_FULL_SIGNATURE: u8 = 0x01;
+/// Expired addresses are deleted in batches of this many rows, other queries run between them
+const EXPIRED_DELETE_BATCH: i64 = 1000;
+/// Values bound for every row of `SQL_BULK_UPSERT_IP`
+const BULK_INSERT_COLUMNS: usize = 11;
+/// Rows in one bulk insert, SQLite before 3.32 allows only 999 values in a statement
+const BULK_INSERT_ROWS: usize = 999 / BULK_INSERT_COLUMNS;
 
 impl SqliteStorage {
+    /// Opens database file `db_name`, or an in-memory database for `IN_MEMORY_DB_PATH`
     pub fn new(db_name: &str) -> Self {
         let db = sqlite::open(db_name).expect("Unable to open sqlite DB");
         db.execute(SQL_CREATE_TABLES).expect("Error creating DB tables");
-        SqliteStorage { db }
+        run_migrations(&db);
+        SqliteStorage { db: Mutex::new(db) }
+    }
+
+    /// Opens database that lives only as long as this storage, for ephemeral trackers
+    pub fn new_in_memory() -> Self {
+        SqliteStorage::new(IN_MEMORY_DB_PATH)
     }
 
-    fn is_address_saved(&self, id: &[u8], client: u32) -> bool {
-        let mut statement = self.db.prepare("SELECT ip FROM clients WHERE id = ? AND client = ?").expect("Error in is_address_saved");
+    /// Returns `(ip, port, priority, ttl_remaining)` of the address saved for this ID and client
+    fn get_saved_address(db: &Connection, id: &[u8], client: ClientId) -> Option<(Vec<u8>, PortNum, Priority, u64)> {
+        let mut statement = db.prepare(SQL_SELECT_SAVED_ROW).expect("Error in get_saved_address");
         statement.bind((1, id)).expect("Error in bind");
         statement.bind((2, client as i64)).expect("Error in bind");
-        return match statement.next().expect("Error in DB") {
-            State::Row => true,
-            State::Done => false
-        };
+        if let State::Row = statement.next().expect("Error in DB") {
+            let ip: Vec<u8> = statement.read(0).unwrap();
+            let port: i64 = statement.read(1).unwrap_or(DEFAULT_PORT as i64);
+            let priority: i64 = statement.read(2).unwrap_or(0);
+            let time: i64 = statement.read(3).unwrap_or(0);
+            let ttl: i64 = statement.read(4).unwrap_or(DEFAULT_TTL as i64);
+            let ttl_remaining = ((time + ttl) as u64).saturating_sub(get_utc_time());
+            return Some((ip, port as u16, priority as u8, ttl_remaining))
+        }
+        None
     }
 
-    fn save_new_address(&self, id: &[u8], ip: &[u8], signature: &[u8], port: u16, priority: u8, client: u32) -> u64 {
-        let mut statement = self.db.prepare(SQL_INSERT_IP).expect("Error in save_new_address");
+    /// Inserts the address or replaces the one saved for this ID and client in one statement
+    fn upsert_address(db: &Connection, id: &[u8], registration: &Registration, ttl: u64) -> bool {
+        let mut statement = db.prepare(SQL_UPSERT_IP).expect("Error in upsert_address");
         statement.bind((1, id)).expect("Error in bind");
-        statement.bind((2, ip)).expect("Error in bind");
-        statement.bind((3, signature)).expect("Error in bind");
-        statement.bind((4, port as i64)).expect("Error in bind");
-        statement.bind((5, priority as i64)).expect("Error in bind");
-        statement.bind((6, client as i64)).expect("Error in bind");
+        statement.bind((2, registration.ip.as_slice())).expect("Error in bind");
+        statement.bind((3, registration.signature.as_slice())).expect("Error in bind");
+        statement.bind((4, registration.port as i64)).expect("Error in bind");
+        statement.bind((5, registration.priority as i64)).expect("Error in bind");
+        statement.bind((6, registration.client as i64)).expect("Error in bind");
         statement.bind((7, get_utc_time() as i64)).expect("Error in bind");
-        statement.bind((8, DEFAULT_TTL as i64)).expect("Error in bind");
+        statement.bind((8, ttl as i64)).expect("Error in bind");
+        statement.bind((9, registration.latency_hint_ms as i64)).expect("Error in bind");
+        statement.bind((10, registration.flags as i64)).expect("Error in bind");
+        statement.bind((11, registration.signed_at as i64)).expect("Error in bind");
         if let State::Done = statement.next().expect("Error in DB") {
-            println!("Saved new address");
-            return UPDATE_TTL
+            debug!("Saved address");
+            return true
         }
-        return ERROR_TTL
+        false
     }
 
-    fn update_address(&self, id: &[u8], ip: &[u8], signature: &[u8], port: u16, priority: u8, client: u32) -> u64 {
-        let mut statement = self.db.prepare(SQL_UPDATE_IP).expect("Error in update_address");
-        statement.bind((1, ip)).expect("Error in bind");
-        statement.bind((2, signature)).expect("Error in bind");
-        statement.bind((3, port as i64)).expect("Error in bind");
-        statement.bind((4, priority as i64)).expect("Error in bind");
-        statement.bind((5, get_utc_time() as i64)).expect("Error in bind");
-        statement.bind((6, DEFAULT_TTL as i64)).expect("Error in bind");
-        statement.bind((7, id)).expect("Error in bind");
-        statement.bind((8, client as i64)).expect("Error in bind");
+    /// Marks the saved address as going offline, returns false if this ip is not saved for this ID and client.
+    /// Only TTL and flags are changed, the saved ip, port, priority and signature are given out until it expires.
+    fn soft_delete_address(&self, id: &[u8], ip: &[u8], client: ClientId) -> bool {
+        let db = self.db.lock().unwrap();
+        let now = get_utc_time() as i64;
+        let mut statement = db.prepare(SQL_SOFT_DELETE_IP).expect("Error in soft_delete_address");
+        statement.bind((1, now)).expect("Error in bind");
+        statement.bind((2, SOFT_DELETE_TTL as i64)).expect("Error in bind");
+        statement.bind((3, ADDR_FLAG_GOING_OFFLINE as i64)).expect("Error in bind");
+        statement.bind((4, id)).expect("Error in bind");
+        statement.bind((5, ip)).expect("Error in bind");
+        statement.bind((6, client as i64)).expect("Error in bind");
+        statement.bind((7, now)).expect("Error in bind");
         if let State::Done = statement.next().expect("Error in DB") {
-            println!("Updated address");
-            return UPDATE_TTL
+            return db.change_count() > 0
         }
-        return ERROR_TTL
+        false
+    }
+
+    fn touch_address(&self, id: &[u8], ip: &[u8], client: ClientId) -> Option<u64> {
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare(SQL_TOUCH_IP).expect("Error in touch_address");
+        statement.bind((1, get_utc_time() as i64)).expect("Error in bind");
+        statement.bind((2, DEFAULT_TTL as i64)).expect("Error in bind");
+        statement.bind((3, id)).expect("Error in bind");
+        statement.bind((4, ip)).expect("Error in bind");
+        statement.bind((5, client as i64)).expect("Error in bind");
+        if let State::Done = statement.next().expect("Error in DB") {
+            if db.change_count() > 0 {
+                return Some(UPDATE_TTL)
+            }
+        }
+        None
     }
 
     fn select_addresses(&self, id: &[u8]) -> Vec<Addr> {
-        let cur_time = get_utc_time();
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare(SQL_SELECT_IPS).expect("Error in select_addresses");
+        statement.bind((1, id)).expect("Error in bind");
+        read_addresses(&mut statement)
+    }
+
+    fn select_addresses_limited(&self, id: &[u8], max_results: u8) -> Vec<Addr> {
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare(SQL_SELECT_IPS_LIMITED).expect("Error in select_addresses_limited");
+        statement.bind((1, id)).expect("Error in bind");
+        statement.bind((2, get_utc_time() as i64)).expect("Error in bind");
+        statement.bind((3, max_results as i64)).expect("Error in bind");
+        read_addresses(&mut statement)
+    }
+
+    fn select_addresses_matching(&self, id: &[u8], filter: &AddressFilter) -> Vec<Addr> {
+        let now = get_utc_time();
+        let (min_client, max_client) = match filter.client {
+            Some(client) => (client, client),
+            None => (0, u32::MAX)
+        };
+        let (min_priority, max_priority) = filter.priority_range.unwrap_or((0, u8::MAX));
+        let min_timestamp = match filter.max_age_secs {
+            0 => 0,
+            max_age_secs => now.saturating_sub(max_age_secs)
+        };
+        // Negative LIMIT means no limit in SQLite
+        let limit = filter.max_results.map(|max| max as i64).unwrap_or(-1);
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare(SQL_SELECT_IPS_MATCHING).expect("Error in select_addresses_matching");
+        statement.bind((1, id)).expect("Error in bind");
+        statement.bind((2, min_client as i64)).expect("Error in bind");
+        statement.bind((3, max_client as i64)).expect("Error in bind");
+        statement.bind((4, min_priority as i64)).expect("Error in bind");
+        statement.bind((5, max_priority as i64)).expect("Error in bind");
+        statement.bind((6, min_timestamp as i64)).expect("Error in bind");
+        statement.bind((7, now as i64)).expect("Error in bind");
+        statement.bind((8, limit)).expect("Error in bind");
+        read_addresses(&mut statement)
+    }
+
+    fn select_addresses_for_ids(&self, ids: &[[u8; 32]]) -> HashMap<[u8; 32], Vec<Addr>> {
+        let mut result: HashMap<[u8; 32], Vec<Addr>> = HashMap::new();
+        if ids.is_empty() {
+            return result;
+        }
+        let placeholders = vec!["?"; ids.len()].join(", ");
+        let sql = SQL_SELECT_IPS_FOR_IDS.replace("IN (?)", &format!("IN ({})", placeholders));
+        let now = get_utc_time();
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare(sql).expect("Error in select_addresses_for_ids");
+        for (index, id) in ids.iter().enumerate() {
+            statement.bind((index + 1, id.as_slice())).expect("Error in bind");
+        }
+        statement.bind((ids.len() + 1, now as i64)).expect("Error in bind");
+        while statement.next().unwrap() == State::Row {
+            let id: Vec<u8> = statement.read(10).unwrap();
+            let (Ok(id), Some(addr)) = (<[u8; 32]>::try_from(id), read_address(&statement, now)) else { continue };
+            result.entry(id).or_default().push(addr);
+        }
+        result
+    }
+
+    fn delete_address(&self, id: &[u8], ip: &[u8], client: ClientId) -> bool {
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare(SQL_DELETE_ADDRESS).expect("Error in delete_address");
+        statement.bind((1, id)).expect("Error in bind");
+        statement.bind((2, ip)).expect("Error in bind");
+        statement.bind((3, client as i64)).expect("Error in bind");
+        if let State::Done = statement.next().expect("Error in DB") {
+            return db.change_count() > 0
+        }
+        false
+    }
+
+    fn delete_id(&self, id: &[u8]) -> u64 {
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare(SQL_DELETE_ID).expect("Error in delete_id");
+        statement.bind((1, id)).expect("Error in bind");
+        if let State::Done = statement.next().expect("Error in DB") {
+            return db.change_count() as u64
+        }
+        0
+    }
+
+    fn select_ids(&self, page: u32, page_size: u32) -> Vec<Vec<u8>> {
+        let mut result = Vec::new();
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare(SQL_SELECT_IDS).expect("Error in select_ids");
+        statement.bind((1, page_size as i64)).expect("Error in bind");
+        statement.bind((2, page as i64 * page_size as i64)).expect("Error in bind");
+        while statement.next().unwrap() == State::Row {
+            let id: Vec<u8> = statement.read(0).unwrap();
+            result.push(id);
+        }
+        result
+    }
+
+    fn select_ids_after(&self, cursor: &[u8], limit: usize) -> Vec<Vec<u8>> {
         let mut result = Vec::new();
-        let mut statement = self.db.prepare(SQL_SELECT_IPS).expect("Error in select_addresses");
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare(SQL_SELECT_IDS_AFTER).expect("Error in select_ids_after");
+        statement.bind((1, cursor)).expect("Error in bind");
+        statement.bind((2, limit as i64)).expect("Error in bind");
+        while statement.next().unwrap() == State::Row {
+            let id: Vec<u8> = statement.read(0).unwrap();
+            result.push(id);
+        }
+        result
+    }
+
+    fn count_rows_and_ids(&self) -> (u64, u64) {
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare(SQL_COUNT_TOTAL).expect("Error in count_rows_and_ids");
+        if let State::Row = statement.next().expect("Error in DB") {
+            let rows: i64 = statement.read(0).unwrap_or(0);
+            let ids: i64 = statement.read(1).unwrap_or(0);
+            return (rows as u64, ids as u64)
+        }
+        (0, 0)
+    }
+
+    fn count_ids_for_ip(&self, ip: &[u8]) -> u64 {
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare(SQL_COUNT_IDS_FOR_IP).expect("Error in count_ids_for_ip");
+        statement.bind((1, ip)).expect("Error in bind");
+        statement.bind((2, get_utc_time() as i64)).expect("Error in bind");
+        if let State::Row = statement.next().expect("Error in DB") {
+            let ids: i64 = statement.read(0).unwrap_or(0);
+            return ids as u64
+        }
+        0
+    }
+
+    fn count_addresses(db: &Connection, id: &[u8]) -> u64 {
+        let mut statement = db.prepare(SQL_COUNT_ADDRESSES_FOR_ID).expect("Error in count_addresses");
         statement.bind((1, id)).expect("Error in bind");
+        statement.bind((2, get_utc_time() as i64)).expect("Error in bind");
+        if let State::Row = statement.next().expect("Error in DB") {
+            let count: i64 = statement.read(0).unwrap_or(0);
+            return count as u64
+        }
+        0
+    }
+
+    fn delete_expired(&self) -> u64 {
+        let now = get_utc_time() as i64;
+        let mut removed = 0;
+        loop {
+            // The lock is taken for every batch, so that requests are not blocked while a big table is cleaned
+            let db = self.db.lock().unwrap();
+            let mut statement = db.prepare(SQL_DELETE_EXPIRED).expect("Error in delete_expired");
+            statement.bind((1, now)).expect("Error in bind");
+            statement.bind((2, EXPIRED_DELETE_BATCH)).expect("Error in bind");
+            if statement.next().expect("Error in DB") != State::Done {
+                return removed;
+            }
+            let deleted = db.change_count() as u64;
+            removed += deleted;
+            if deleted < EXPIRED_DELETE_BATCH as u64 {
+                return removed;
+            }
+        }
+    }
+
+    fn insert_tombstone(&self, tombstone: &Tombstone) -> bool {
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare(SQL_INSERT_TOMBSTONE).expect("Error in insert_tombstone");
+        statement.bind((1, tombstone.id.as_slice())).expect("Error in bind");
+        statement.bind((2, tombstone.ip.as_slice())).expect("Error in bind");
+        statement.bind((3, tombstone.deleted_at as i64)).expect("Error in bind");
+        statement.bind((4, tombstone.signature.as_slice())).expect("Error in bind");
+        if statement.next().expect("Error in DB") != State::Done {
+            return false
+        }
+        // Older registrations would be skipped as fresh on re-registration if they were only hidden
+        let mut statement = db.prepare(SQL_DELETE_TOMBSTONED).expect("Error in insert_tombstone");
+        statement.bind((1, tombstone.id.as_slice())).expect("Error in bind");
+        statement.bind((2, tombstone.ip.as_slice())).expect("Error in bind");
+        statement.bind((3, tombstone.deleted_at as i64)).expect("Error in bind");
+        statement.next().expect("Error in DB") == State::Done
+    }
+
+    fn select_tombstones(&self, since: u64) -> Vec<Tombstone> {
+        let mut result = Vec::new();
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare(SQL_SELECT_TOMBSTONES_SINCE).expect("Error in select_tombstones");
+        statement.bind((1, since as i64)).expect("Error in bind");
         while statement.next().unwrap() == State::Row {
-            let ip: Vec<u8> = statement.read(0).unwrap();
-            let signature: Vec<u8> = statement.read(1).unwrap();
-            let port: i64 = statement.read(2).unwrap_or(DEFAULT_PORT as i64);
-            let priority: i64 = statement.read(3).unwrap_or(0);
-            let client: i64 = statement.read(4).unwrap_or(0);
-            let time: i64 = statement.read(5).unwrap_or(0i64);
-            let ttl: i64 = statement.read(6).unwrap_or(DEFAULT_TTL as i64);
-            let expire = time + ttl;
-            //println!("time: {}, ttl: {}, expire: {}, cur_time: {}", time, ttl, expire, cur_time);
-            //println!("Got something {:?}", &ip);
-            if cur_time > (expire as u64) {
-                continue;
+            let id: Vec<u8> = statement.read(0).unwrap();
+            let ip: Vec<u8> = statement.read(1).unwrap();
+            let deleted_at: i64 = statement.read(2).unwrap_or(0);
+            let signature: Vec<u8> = statement.read(3).unwrap();
+            result.push(Tombstone { id, ip, deleted_at: deleted_at as u64, signature });
+        }
+        result
+    }
+
+    fn insert_ban(&self, ban: &Ban) -> Result<(), MimirError> {
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare(SQL_UPSERT_BAN)?;
+        statement.bind((1, ban.ip_cidr.as_str()))?;
+        statement.bind((2, ban.banned_at as i64))?;
+        statement.bind((3, ban.expires_at as i64))?;
+        statement.bind((4, ban.reason.as_str()))?;
+        statement.next()?;
+        Ok(())
+    }
+
+    fn select_bans(&self, ip_cidr: Option<&str>) -> Vec<Ban> {
+        let mut result = Vec::new();
+        let db = self.db.lock().unwrap();
+        let mut statement = match ip_cidr {
+            Some(ip_cidr) => {
+                let mut statement = db.prepare(SQL_SELECT_BAN).expect("Error in select_bans");
+                statement.bind((1, ip_cidr)).expect("Error in bind");
+                statement.bind((2, get_utc_time() as i64)).expect("Error in bind");
+                statement
             }
-            result.push(Addr { ip, signature, port: port as u16, priority: priority as u8, client: client as u32, ttl: ttl as u64 })
+            None => {
+                let mut statement = db.prepare(SQL_SELECT_ACTIVE_BANS).expect("Error in select_bans");
+                statement.bind((1, get_utc_time() as i64)).expect("Error in bind");
+                statement
+            }
+        };
+        while statement.next().unwrap() == State::Row {
+            let ip_cidr: String = statement.read(0).unwrap();
+            let banned_at: i64 = statement.read(1).unwrap_or(0);
+            let expires_at: i64 = statement.read(2).unwrap_or(0);
+            let reason: String = statement.read(3).unwrap_or_default();
+            result.push(Ban { ip_cidr, banned_at: banned_at as u64, expires_at: expires_at as u64, reason });
         }
         result
     }
+
+    fn delete_expired_bans(&self) -> u64 {
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare(SQL_DELETE_EXPIRED_BANS).expect("Error in delete_expired_bans");
+        statement.bind((1, get_utc_time() as i64)).expect("Error in bind");
+        if let State::Done = statement.next().expect("Error in DB") {
+            return db.change_count() as u64
+        }
+        0
+    }
+
+    fn delete_old_tombstones(&self) -> u64 {
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare(SQL_DELETE_OLD_TOMBSTONES).expect("Error in delete_old_tombstones");
+        statement.bind((1, get_utc_time().saturating_sub(TOMBSTONE_TTL) as i64)).expect("Error in bind");
+        if let State::Done = statement.next().expect("Error in DB") {
+            return db.change_count() as u64
+        }
+        0
+    }
 }
 
-impl Storage for SqliteStorage {
-    fn save_address(&self, id: &[u8], ip: &[u8], signature: &[u8], port: u16, priority: u8, client: u32) -> u64 {
-        if !self.is_address_saved(id, client) {
-            return self.save_new_address(id, ip, signature, port, priority, client);
+impl SqliteStorage {
+    /// Imports addresses from NDJSON file, one object per line with HEX `id`, `ip` and `signature`.
+    /// Saved addresses of the same ID and client are replaced, everything is imported in one transaction.
+    pub fn import_from_json(&self, path: &str, skip_sig_check: bool) -> Result<ImportReport, MimirError> {
+        let content = fs::read_to_string(path)?;
+        let (entries, mut report) = parse_import_lines(&content, skip_sig_check);
+        report.inserted = self.bulk_import_raw(&entries)?;
+        Ok(report)
+    }
+
+    /// Saves addresses with many rows in one statement, replacing saved addresses of the same ID and client.
+    /// Signatures are not checked. Either all entries are saved, or none if there is an error.
+    pub fn bulk_import_raw(&self, entries: &[RawEntry]) -> Result<u64, MimirError> {
+        let db = self.db.lock().unwrap();
+        db.execute(SQL_BEGIN)?;
+        match insert_entries(&db, entries) {
+            Ok(()) => {
+                db.execute(SQL_COMMIT)?;
+                Ok(entries.len() as u64)
+            }
+            Err(e) => {
+                db.execute(SQL_ROLLBACK)?;
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Writes all saved addresses as CSV with a header, expired ones too, and returns the number of written rows
+    pub fn export_csv(&self, writer: &mut dyn Write) -> Result<u64, MimirError> {
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare(SQL_SELECT_ALL_IPS)?;
+        writeln!(writer, "id_hex,ip_hex,port,priority,client,timestamp_utc,ttl_secs,expires_utc,signature_hex")?;
+        let mut rows = 0;
+        while statement.next()? == State::Row {
+            let id: Vec<u8> = statement.read(0)?;
+            let ip: Vec<u8> = statement.read(1)?;
+            let port: i64 = statement.read(2)?;
+            let priority: i64 = statement.read(3)?;
+            let client: i64 = statement.read(4)?;
+            let timestamp = statement.read::<i64, _>(5)? as u64;
+            let ttl = statement.read::<i64, _>(6)? as u64;
+            let signature: Vec<u8> = statement.read(7)?;
+            writeln!(writer, "{},{},{},{},{},{},{},{},{}", to_hex(&id), to_hex(&ip), port, priority, client,
+                     format_utc_time(timestamp), ttl, format_utc_time(timestamp + ttl), to_hex(&signature))?;
+            rows += 1;
         }
-        self.update_address(id, ip, signature, port, priority, client)
+        Ok(rows)
+    }
+}
+
+/// Parses lines of NDJSON import, leaving `inserted` of the report 0
+fn parse_import_lines(content: &str, skip_sig_check: bool) -> (Vec<RawEntry>, ImportReport) {
+    let now = get_utc_time();
+    let mut entries = Vec::new();
+    let mut report = ImportReport::default();
+    for line in content.lines().filter(|line| !line.trim().is_empty()) {
+        let record = match serde_json::from_str::<ImportRecord>(line) {
+            Ok(record) => record,
+            Err(_) => {
+                report.errors += 1;
+                continue;
+            }
+        };
+        let (id, ip, signature): ([u8; 32], [u8; 16], [u8; 64]) = match (from_hex_array(&record.id), from_hex_array(&record.ip), from_hex_array(&record.signature)) {
+            (Some(id), Some(ip), Some(signature)) => (id, ip, signature),
+            _ => {
+                report.errors += 1;
+                continue;
+            }
+        };
+        if record.timestamp + record.ttl < now {
+            report.skipped_expired += 1;
+            continue;
+        }
+        let flags = record.flags & ADDR_FLAG_FULL_SIGNATURE;
+        let signature_ok = match flags != 0 {
+            true => check_registration_signature(&id, &ip, record.port, record.priority, record.client, record.signed_at, &signature),
+            false => check_ip_signature(&id, &signature, &ip, record.signed_at)
+        };
+        if !skip_sig_check && !signature_ok {
+            report.skipped_invalid_sig += 1;
+            continue;
+        }
+        entries.push(RawEntry {
+            id,
+            ip,
+            signature,
+            port: record.port,
+            priority: record.priority,
+            client: record.client,
+            timestamp: record.timestamp,
+            ttl: record.ttl,
+            latency_hint_ms: record.latency_hint_ms,
+            signed_at: record.signed_at,
+            flags
+        });
+    }
+    (entries, report)
+}
+
+/// Inserts entries by `BULK_INSERT_ROWS` in one statement, the caller handles the transaction
+fn insert_entries(db: &Connection, entries: &[RawEntry]) -> Result<(), sqlite::Error> {
+    let (values, conflict) = SQL_BULK_UPSERT_IP.split_once(" ON CONFLICT").expect("Error in SQL_BULK_UPSERT_IP");
+    let (insert, row) = values.split_once(" VALUES ").expect("Error in SQL_BULK_UPSERT_IP");
+    for chunk in entries.chunks(BULK_INSERT_ROWS) {
+        let sql = format!("{} VALUES {} ON CONFLICT{}", insert, vec![row; chunk.len()].join(", "), conflict);
+        let mut statement = db.prepare(sql)?;
+        for (index, entry) in chunk.iter().enumerate() {
+            let first = index * BULK_INSERT_COLUMNS;
+            statement.bind((first + 1, entry.id.as_slice()))?;
+            statement.bind((first + 2, entry.ip.as_slice()))?;
+            statement.bind((first + 3, entry.signature.as_slice()))?;
+            statement.bind((first + 4, entry.port as i64))?;
+            statement.bind((first + 5, entry.priority as i64))?;
+            statement.bind((first + 6, entry.client as i64))?;
+            statement.bind((first + 7, entry.timestamp as i64))?;
+            statement.bind((first + 8, entry.ttl as i64))?;
+            statement.bind((first + 9, entry.latency_hint_ms as i64))?;
+            statement.bind((first + 10, entry.signed_at as i64))?;
+            statement.bind((first + 11, entry.flags as i64))?;
+        }
+        statement.next()?;
+    }
+    Ok(())
+}
+
+/// Brings the schema of an existing database up to date using `user_version` pragma
+pub fn run_migrations(db: &Connection) {
+    let mut statement = db.prepare(SQL_GET_DB_VERSION).expect("Error in run_migrations");
+    let version: i64 = match statement.next().expect("Error in DB") {
+        State::Row => statement.read(0).unwrap_or(0),
+        State::Done => 0
+    };
+    drop(statement);
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        // A step and its version are saved together, a tracker killed in between doesn't run the step again
+        db.execute(SQL_BEGIN_IMMEDIATE).expect("Error migrating DB");
+        let result = db.execute(migration).and_then(|_| db.execute(format!("PRAGMA user_version = {}", index + 1)));
+        if let Err(e) = result {
+            db.execute(SQL_ROLLBACK).expect("Error in DB");
+            panic!("Error migrating DB to version {}: {}", index + 1, e);
+        }
+        db.execute(SQL_COMMIT).expect("Error migrating DB");
+        info!("Migrated DB to version {}", index + 1);
+    }
+}
+
+/// Span of one storage operation, `id` is empty for operations on the whole DB
+fn storage_span(op: &'static str, id: &[u8]) -> Span {
+    let span = info_span!("storage", op, id_hex = field::Empty, rows_affected = field::Empty, rows_returned = field::Empty);
+    if !span.is_disabled() && !id.is_empty() {
+        span.record("id_hex", to_hex(id));
+    }
+    span
+}
+
+/// Returns `ttl` if the address was written, or `ERROR_TTL` to make the client retry soon
+fn ttl_if_saved(saved: bool, ttl: u64) -> u64 {
+    if saved { ttl } else { ERROR_TTL }
+}
+
+/// Reads all not expired addresses from the rows of executed statement
+fn read_addresses(statement: &mut Statement) -> Vec<Addr> {
+    let cur_time = get_utc_time();
+    let mut result = Vec::new();
+    while statement.next().unwrap() == State::Row {
+        result.extend(read_address(statement, cur_time));
+    }
+    result
+}
+
+/// Reads address from the current row, or `None` if it is expired
+fn read_address(statement: &Statement, cur_time: u64) -> Option<Addr> {
+    let ip: Vec<u8> = statement.read(0).unwrap();
+    let signature: Vec<u8> = statement.read(1).unwrap();
+    let port: i64 = statement.read(2).unwrap_or(DEFAULT_PORT as i64);
+    let priority: i64 = statement.read(3).unwrap_or(0);
+    let client: i64 = statement.read(4).unwrap_or(0);
+    let time: i64 = statement.read(5).unwrap_or(0i64);
+    let ttl: i64 = statement.read(6).unwrap_or(DEFAULT_TTL as i64);
+    let latency_hint_ms: i64 = statement.read(7).unwrap_or(0);
+    let flags: i64 = statement.read(8).unwrap_or(0);
+    let signed_at: i64 = statement.read(9).unwrap_or(0);
+    let expire = time + ttl;
+    if cur_time > (expire as u64) {
+        return None;
+    }
+    Some(Addr { ip, signature, port: port as u16, priority: priority as u8, client: client as u32, ttl: ttl as u64, latency_hint_ms: latency_hint_ms as u16, flags: flags as u8, signed_at: signed_at as u32 })
+}
+
+impl Storage for SqliteStorage {
+    fn save_address(&self, id: &[u8], registration: &Registration, soft_delete: bool) -> u64 {
+        let span = storage_span("save_address", id);
+        let ttl = span.in_scope(|| {
+            if soft_delete {
+                return match self.soft_delete_address(id, &registration.ip, registration.client) {
+                    true => SOFT_DELETE_TTL,
+                    false => 0
+                }
+            }
+            let db = self.db.lock().unwrap();
+            ttl_if_saved(SqliteStorage::upsert_address(&db, id, registration, DEFAULT_TTL), UPDATE_TTL)
+        });
+        span.record("rows_affected", (ttl != 0 && ttl != ERROR_TTL) as u64);
+        ttl
+    }
+
+    fn register_or_skip(&self, id: &[u8], registration: &Registration, new_ttl: u64, max_addresses: Option<u64>) -> RegistrationResult {
+        let span = storage_span("register_or_skip", id);
+        let result = span.in_scope(|| {
+            // One lock for the check and the write, other threads can't save addresses of this ID in between
+            let db = self.db.lock().unwrap();
+            let saved = SqliteStorage::get_saved_address(&db, id, registration.client);
+            // Expired addresses are not counted, so saving over one adds to the count like a new address
+            let adds_address = saved.as_ref().is_none_or(|(.., ttl_remaining)| *ttl_remaining == 0);
+            let action = match saved {
+                Some((saved_ip, saved_port, saved_priority, ttl_remaining)) => {
+                    if saved_ip == registration.ip && saved_port == registration.port && saved_priority == registration.priority && ttl_remaining > new_ttl / 2 {
+                        return RegistrationResult { action: RegistrationAction::Skipped, ttl: ttl_remaining };
+                    }
+                    RegistrationAction::Updated
+                }
+                None => RegistrationAction::Inserted
+            };
+            let Some(max) = max_addresses.filter(|_| adds_address) else {
+                let ttl = ttl_if_saved(SqliteStorage::upsert_address(&db, id, registration, new_ttl), new_ttl);
+                return RegistrationResult { action, ttl };
+            };
+            // The transaction keeps other processes with this DB from saving between the count and the insert
+            if db.execute(SQL_BEGIN_IMMEDIATE).is_err() {
+                return RegistrationResult { action, ttl: ERROR_TTL };
+            }
+            if SqliteStorage::count_addresses(&db, id) >= max {
+                db.execute(SQL_ROLLBACK).expect("Error in DB");
+                return RegistrationResult { action: RegistrationAction::LimitReached, ttl: 0 };
+            }
+            let saved = SqliteStorage::upsert_address(&db, id, registration, new_ttl);
+            db.execute(SQL_COMMIT).expect("Error in DB");
+            RegistrationResult { action, ttl: ttl_if_saved(saved, new_ttl) }
+        });
+        let written = matches!(result.action, RegistrationAction::Inserted | RegistrationAction::Updated) && result.ttl != ERROR_TTL;
+        span.record("rows_affected", written as u64);
+        result
+    }
+
+    fn touch(&self, id: &[u8], ip: &[u8], client: ClientId) -> Option<u64> {
+        self.touch_address(id, ip, client)
     }
 
     fn get_addresses(&self, id: &[u8]) -> Vec<Addr> {
-        self.select_addresses(id)
+        let span = storage_span("get_addresses", id);
+        let result = span.in_scope(|| dedup_addrs(self.select_addresses(id)));
+        span.record("rows_returned", result.len());
+        result
+    }
+
+    fn get_peer_addresses(&self, ids: &[[u8; 32]]) -> HashMap<[u8; 32], Vec<Addr>> {
+        let span = storage_span("get_peer_addresses", &[]);
+        let result = span.in_scope(|| self.select_addresses_for_ids(ids));
+        span.record("rows_returned", result.values().map(Vec::len).sum::<usize>());
+        result
+    }
+
+    fn get_addresses_filtered(&self, id: &[u8], max_results: u8) -> Vec<Addr> {
+        let span = storage_span("get_addresses_filtered", id);
+        let result = span.in_scope(|| self.select_addresses_limited(id, max_results));
+        span.record("rows_returned", result.len());
+        result
+    }
+
+    fn get_addresses_for_client(&self, id: &[u8], client: ClientId) -> Vec<Addr> {
+        self.select_addresses_matching(id, &AddressFilter { client: Some(client), ..Default::default() })
+    }
+
+    fn get_addresses_recent(&self, id: &[u8], max_age_secs: u64) -> Vec<Addr> {
+        self.select_addresses_matching(id, &AddressFilter { max_age_secs, ..Default::default() })
+    }
+
+    fn get_addresses_by_priority_range(&self, id: &[u8], min_priority: Priority, max_priority: Priority) -> Vec<Addr> {
+        self.select_addresses_matching(id, &AddressFilter { priority_range: Some((min_priority, max_priority)), ..Default::default() })
+    }
+
+    fn find_addresses(&self, id: &[u8], filter: &AddressFilter) -> Vec<Addr> {
+        let span = storage_span("find_addresses", id);
+        let result = span.in_scope(|| self.select_addresses_matching(id, filter));
+        span.record("rows_returned", result.len());
+        result
+    }
+
+    fn remove_address(&self, id: &[u8], ip: &[u8], client: ClientId) -> bool {
+        self.delete_address(id, ip, client)
+    }
+
+    fn prune_id(&self, id: &[u8]) -> u64 {
+        self.delete_id(id)
+    }
+
+    fn get_all_ids(&self, page: u32, page_size: u32) -> Vec<Vec<u8>> {
+        self.select_ids(page, page_size)
+    }
+
+    fn list_ids_paginated(&self, cursor: Option<Vec<u8>>, limit: usize) -> (Vec<Vec<u8>>, Option<Vec<u8>>) {
+        // Empty BLOB is ordered before all IDs
+        let ids = self.select_ids_after(cursor.as_deref().unwrap_or_default(), limit);
+        let next_cursor = match ids.len() == limit {
+            true => ids.last().cloned(),
+            false => None
+        };
+        (ids, next_cursor)
+    }
+
+    fn count_total(&self) -> (u64, u64) {
+        self.count_rows_and_ids()
+    }
+
+    fn count_active_ids_for_ip(&self, ip: &[u8]) -> u64 {
+        self.count_ids_for_ip(ip)
+    }
+
+    fn count_addresses_for_id(&self, id: &[u8]) -> u64 {
+        let db = self.db.lock().unwrap();
+        SqliteStorage::count_addresses(&db, id)
+    }
+
+    fn add_tombstone(&self, tombstone: &Tombstone) -> bool {
+        self.insert_tombstone(tombstone)
+    }
+
+    fn get_tombstones_since(&self, since: u64) -> Vec<Tombstone> {
+        self.select_tombstones(since)
+    }
+
+    fn add_ban(&self, ban: &Ban) -> Result<(), MimirError> {
+        self.insert_ban(ban)
     }
+
+    fn get_ban(&self, ip_cidr: &str) -> Option<Ban> {
+        self.select_bans(Some(ip_cidr)).pop()
+    }
+
+    fn get_active_bans(&self) -> Vec<Ban> {
+        self.select_bans(None)
+    }
+
+    fn add_rejected_id(&self, id: &[u8], rejected_at: u64, reason: &str) -> Result<(), MimirError> {
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare(SQL_UPSERT_REJECTED_ID)?;
+        statement.bind((1, id))?;
+        statement.bind((2, rejected_at as i64))?;
+        statement.bind((3, reason))?;
+        statement.next()?;
+        Ok(())
+    }
+
+    fn get_rejected_ids(&self) -> Vec<Vec<u8>> {
+        let mut result = Vec::new();
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare(SQL_SELECT_REJECTED_IDS).expect("Error in get_rejected_ids");
+        while statement.next().unwrap() == State::Row {
+            result.push(statement.read(0).unwrap());
+        }
+        result
+    }
+
+    fn cleanup_expired(&self) -> u64 {
+        let span = storage_span("cleanup_expired", &[]);
+        let removed = span.in_scope(|| {
+            self.delete_old_tombstones();
+            self.delete_expired_bans();
+            self.delete_expired()
+        });
+        span.record("rows_affected", removed);
+        removed
+    }
+
+    fn vacuum(&self, full: bool) -> Result<(), MimirError> {
+        let _enter = storage_span("vacuum", &[]).entered();
+        let sql = if full { SQL_VACUUM } else { SQL_INCREMENTAL_VACUUM };
+        self.db.lock().unwrap().execute(sql)?;
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), MimirError> {
+        let _enter = storage_span("flush", &[]).entered();
+        self.db.lock().unwrap().execute(SQL_WAL_CHECKPOINT)?;
+        Ok(())
+    }
+
+    fn get_setting(&self, name: &str) -> Option<Vec<u8>> {
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare(SQL_SELECT_SETTING).expect("Error in get_setting");
+        statement.bind((1, name)).expect("Error in bind");
+        match statement.next().expect("Error in DB") {
+            State::Row => statement.read(0).ok(),
+            State::Done => None
+        }
+    }
+
+    fn set_setting(&self, name: &str, value: &[u8]) -> Result<(), MimirError> {
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare(SQL_UPSERT_SETTING)?;
+        statement.bind((1, name))?;
+        statement.bind((2, value))?;
+        statement.next()?;
+        Ok(())
+    }
+}
+
+/// Conditions of address lookups, the default one matches all not expired addresses
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AddressFilter {
+    /// Only addresses registered by this client type
+    pub client: Option<ClientId>,
+    /// Only addresses saved less than this many seconds ago, 0 for any
+    pub max_age_secs: u64,
+    /// Only addresses with priority in this inclusive range (min, max)
+    pub priority_range: Option<(Priority, Priority)>,
+    pub max_results: Option<u8>
+}
+
+/// One line of NDJSON import, `timestamp` is the UTC time the address was saved at
+#[derive(Deserialize)]
+struct ImportRecord {
+    id: String,
+    ip: String,
+    signature: String,
+    port: PortNum,
+    priority: Priority,
+    client: ClientId,
+    timestamp: u64,
+    ttl: u64,
+    #[serde(default)]
+    latency_hint_ms: u16,
+    #[serde(default)]
+    signed_at: u32,
+    #[serde(default)]
+    flags: u8
+}
+
+/// Address of an ID as the node sent it in a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Registration {
+    pub ip: [u8; 16],
+    /// Signature of the ip, or of `registration_signed_data` with `ADDR_FLAG_FULL_SIGNATURE` in `flags`
+    pub signature: [u8; 64],
+    /// Request timestamp covered by `signature`, 0 if only `ip` is signed
+    pub signed_at: u32,
+    pub port: PortNum,
+    pub priority: Priority,
+    pub client: ClientId,
+    pub latency_hint_ms: u16,
+    pub flags: u8
+}
+
+/// Address to import as is, with the time it was saved at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawEntry {
+    pub id: [u8; 32],
+    pub ip: [u8; 16],
+    pub signature: [u8; 64],
+    pub port: PortNum,
+    pub priority: Priority,
+    pub client: ClientId,
+    /// UTC time in seconds
+    pub timestamp: u64,
+    pub ttl: u64,
+    pub latency_hint_ms: u16,
+    pub signed_at: u32,
+    /// Only `ADDR_FLAG_FULL_SIGNATURE` is meaningful, going offline is not imported
+    pub flags: u8
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ImportReport {
+    pub inserted: u64,
+    pub skipped_expired: u64,
+    pub skipped_invalid_sig: u64,
+    /// Lines that are not valid JSON or have wrong field values
+    pub errors: u64
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegistrationAction {
+    Skipped,
+    Inserted,
+    Updated,
+    /// The ID has as many addresses as allowed, nothing is saved and TTL is 0
+    LimitReached
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegistrationResult {
+    pub action: RegistrationAction,
+    /// TTL in seconds the address is stored with, or `ERROR_TTL` if it was not saved
+    pub ttl: u64
 }
 
+/// Application that registered the address, an ID can have one address per client
+pub type ClientId = u32;
+/// Higher priority addresses are returned first
+pub type Priority = u8;
+pub type PortNum = u16;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Addr {
+    #[serde(with = "crate::serde_hex")]
     pub ip: Vec<u8>,
+    #[serde(with = "crate::serde_hex")]
     pub signature: Vec<u8>,
-    pub port: u16,
-    pub priority: u8,
-    pub client: u32,
-    pub ttl: u64
+    pub port: PortNum,
+    pub priority: Priority,
+    pub client: ClientId,
+    pub ttl: u64,
+    /// Round-trip time the node measured to well-known anchors, 0 if unknown
+    pub latency_hint_ms: u16,
+    /// `ADDR_FLAG_GOING_OFFLINE` and other flags
+    pub flags: u8,
+    /// Request timestamp covered by `signature`, 0 if only `ip` is signed
+    pub signed_at: u32
+}
+
+/// Record of a deleted address, hides older registrations of this `ip` here and on peer trackers
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tombstone {
+    #[serde(with = "crate::serde_hex")]
+    pub id: Vec<u8>,
+    #[serde(with = "crate::serde_hex")]
+    pub ip: Vec<u8>,
+    /// UTC time in seconds
+    pub deleted_at: u64,
+    /// Signature of `signed_data()` by the ID key
+    #[serde(with = "crate::serde_hex")]
+    pub signature: Vec<u8>
+}
+
+impl Tombstone {
+    /// Returns `id || ip || deleted_at`, where `deleted_at` is big endian
+    pub fn signed_data(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.id.len() + self.ip.len() + 8);
+        data.extend_from_slice(&self.id);
+        data.extend_from_slice(&self.ip);
+        data.extend_from_slice(&self.deleted_at.to_be_bytes());
+        data
+    }
+}
+
+/// Ban of an IP or subnet, `ip_cidr` is like `200:1234::/48`, IPv4 ones are kept as IPv4-mapped IPv6
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ban {
+    pub ip_cidr: String,
+    /// UTC time in seconds
+    pub banned_at: u64,
+    pub expires_at: u64,
+    pub reason: String
+}
+
+/// Only `ip`, `port` and `client` identify an address, `priority` and `ttl` can differ between sources
+impl Hash for Addr {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.ip.hash(state);
+        self.port.hash(state);
+        self.client.hash(state);
+    }
 }
 
 pub fn get_utc_time() -> u64 {
     let sys_time = std::time::SystemTime::now();
     let elapsed = sys_time.duration_since(std::time::UNIX_EPOCH).unwrap();
     elapsed.as_secs()
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::functions::from_hex;
+    use crate::test_helpers::{generate_keypairs, sign_ip};
+
+    fn registration(key: &ed25519_dalek::Keypair, ip: [u8; 16], port: PortNum, client: ClientId) -> Registration {
+        Registration { ip, signature: sign_ip(key, ip, 0), signed_at: 0, port, priority: 1, client, latency_hint_ms: 0, flags: 0 }
+    }
+
+    #[test]
+    fn soft_delete_keeps_saved_address() {
+        let storage = SqliteStorage::new_in_memory();
+        let (key, id) = &generate_keypairs(1)[0];
+        let saved = registration(key, [1; 16], 5000, 7);
+        storage.save_address(id, &saved, false);
+
+        let deregistration = Registration { port: 6000, priority: 9, ..registration(key, [1; 16], 6000, 7) };
+        assert_eq!(storage.save_address(id, &deregistration, true), SOFT_DELETE_TTL);
+
+        let addrs = storage.get_addresses(id);
+        assert_eq!(addrs.len(), 1);
+        assert_eq!(addrs[0].ip, saved.ip.to_vec());
+        assert_eq!(addrs[0].port, 5000);
+        assert_eq!(addrs[0].priority, 1);
+        assert_eq!(addrs[0].signature, saved.signature.to_vec());
+        assert_eq!(addrs[0].flags & ADDR_FLAG_GOING_OFFLINE, ADDR_FLAG_GOING_OFFLINE);
+        assert!(addrs[0].ttl <= SOFT_DELETE_TTL);
+    }
+
+    #[test]
+    fn soft_delete_of_other_ip_changes_nothing() {
+        let storage = SqliteStorage::new_in_memory();
+        let (key, id) = &generate_keypairs(1)[0];
+        storage.save_address(id, &registration(key, [1; 16], 5000, 7), false);
+
+        assert_eq!(storage.save_address(id, &registration(key, [2; 16], 5000, 7), true), 0);
+        assert_eq!(storage.save_address(id, &registration(key, [1; 16], 5000, 8), true), 0);
+
+        let addrs = storage.get_addresses(id);
+        assert_eq!(addrs.len(), 1);
+        assert_eq!(addrs[0].flags & ADDR_FLAG_GOING_OFFLINE, 0);
+        assert!(addrs[0].ttl > SOFT_DELETE_TTL);
+    }
+
+    #[test]
+    fn register_refuses_addresses_over_limit() {
+        let storage = SqliteStorage::new_in_memory();
+        let (key, id) = &generate_keypairs(1)[0];
+        for client in 0..3 {
+            let result = storage.register_or_skip(id, &registration(key, [1; 16], 5000, client), DEFAULT_TTL, Some(3));
+            assert_eq!(result.action, RegistrationAction::Inserted);
+        }
+        let result = storage.register_or_skip(id, &registration(key, [1; 16], 5000, 3), DEFAULT_TTL, Some(3));
+        assert_eq!(result, RegistrationResult { action: RegistrationAction::LimitReached, ttl: 0 });
+        assert_eq!(storage.count_addresses_for_id(id), 3);
+        // Saved addresses can still change
+        let result = storage.register_or_skip(id, &registration(key, [2; 16], 5000, 0), DEFAULT_TTL, Some(3));
+        assert_eq!(result.action, RegistrationAction::Updated);
+        // And there is no limit without one
+        let result = storage.register_or_skip(id, &registration(key, [1; 16], 5000, 3), DEFAULT_TTL, None);
+        assert_eq!(result.action, RegistrationAction::Inserted);
+    }
+
+    #[test]
+    fn expired_addresses_are_not_counted_for_limit() {
+        let storage = SqliteStorage::new_in_memory();
+        let (key, id) = &generate_keypairs(1)[0];
+        storage.register_or_skip(id, &registration(key, [1; 16], 5000, 0), 0, Some(1));
+        let result = storage.register_or_skip(id, &registration(key, [1; 16], 5000, 1), DEFAULT_TTL, Some(1));
+        assert_eq!(result.action, RegistrationAction::Inserted);
+    }
+
+    #[test]
+    fn prune_id_removes_all_addresses() {
+        let storage = SqliteStorage::new_in_memory();
+        let keys = generate_keypairs(2);
+        let (key, id) = &keys[0];
+        for client in 0..5 {
+            storage.save_address(id, &registration(key, [client as u8; 16], 5000, client), false);
+        }
+        let (other_key, other_id) = &keys[1];
+        storage.save_address(other_id, &registration(other_key, [1; 16], 5000, 0), false);
+        assert_eq!(storage.prune_id(id), 5);
+        assert!(storage.get_addresses(id).is_empty());
+        assert_eq!(storage.get_addresses(other_id).len(), 1);
+        assert_eq!(storage.prune_id(id), 0);
+    }
+
+    #[test]
+    fn peer_addresses_of_several_ids() {
+        let storage = SqliteStorage::new_in_memory();
+        let keys = generate_keypairs(4);
+        for (index, (key, id)) in keys.iter().take(3).enumerate() {
+            for client in 0..=index as u32 {
+                storage.save_address(id, &registration(key, [index as u8; 16], 5000, client), false);
+            }
+        }
+        let ids: Vec<[u8; 32]> = keys.iter().map(|(_, id)| *id).collect();
+        let found = storage.get_peer_addresses(&ids);
+        assert_eq!(found.len(), 3);
+        for (index, id) in ids.iter().take(3).enumerate() {
+            assert_eq!(found[id].len(), index + 1);
+            assert!(found[id].iter().all(|addr| addr.ip == vec![index as u8; 16]));
+        }
+        assert!(!found.contains_key(&ids[3]));
+        assert!(storage.get_peer_addresses(&[]).is_empty());
+    }
+
+    #[test]
+    fn export_csv_can_be_parsed_back() {
+        let storage = SqliteStorage::new_in_memory();
+        let (key, id) = &generate_keypairs(1)[0];
+        let saved = [registration(key, [1; 16], 5000, 7), registration(key, [2; 16], 6000, 8)];
+        saved.iter().for_each(|registration| { storage.save_address(id, registration, false); });
+        let mut csv = Vec::new();
+        assert_eq!(storage.export_csv(&mut csv).unwrap(), 2);
+
+        let csv = String::from_utf8(csv).unwrap();
+        let mut lines = csv.lines();
+        assert_eq!(lines.next(), Some("id_hex,ip_hex,port,priority,client,timestamp_utc,ttl_secs,expires_utc,signature_hex"));
+        let rows: Vec<Vec<&str>> = lines.map(|line| line.split(',').collect()).collect();
+        assert_eq!(rows.len(), 2);
+        for (row, registration) in rows.iter().zip(saved.iter()) {
+            assert_eq!(row.len(), 9);
+            assert_eq!(from_hex(row[0]).unwrap(), id.to_vec());
+            assert_eq!(from_hex(row[1]).unwrap(), registration.ip.to_vec());
+            assert_eq!(row[2].parse::<PortNum>().unwrap(), registration.port);
+            assert_eq!(row[3].parse::<Priority>().unwrap(), registration.priority);
+            assert_eq!(row[4].parse::<ClientId>().unwrap(), registration.client);
+            assert_eq!(row[6].parse::<u64>().unwrap(), DEFAULT_TTL);
+            assert!(row[5].ends_with('Z') && row[5].len() == 20 && row[5] < row[7]);
+            assert_eq!(from_hex(row[8]).unwrap(), registration.signature.to_vec());
+        }
+    }
+
+    #[test]
+    fn clones_are_equal() {
+        let addr = Addr { ip: vec![1; 16], signature: vec![2; 64], port: 5000, priority: 1, client: 7, ttl: 600, latency_hint_ms: 20, flags: ADDR_FLAG_GOING_OFFLINE, signed_at: 1 };
+        assert_eq!(addr.clone(), addr);
+        let tombstone = Tombstone { id: vec![3; 32], ip: vec![1; 16], deleted_at: 100, signature: vec![4; 64] };
+        assert_eq!(tombstone.clone(), tombstone);
+        let result = RegistrationResult { action: RegistrationAction::Updated, ttl: UPDATE_TTL };
+        assert_eq!(result.clone(), result);
+        let mut changed = addr.clone();
+        changed.ip[0] = 9;
+        assert_ne!(changed, addr);
+    }
+
+    fn user_version(db: &Connection) -> i64 {
+        let mut statement = db.prepare(SQL_GET_DB_VERSION).unwrap();
+        statement.next().unwrap();
+        statement.read(0).unwrap()
+    }
+
+    #[test]
+    fn migrations_run_once() {
+        let db = sqlite::open(IN_MEMORY_DB_PATH).unwrap();
+        db.execute(SQL_CREATE_TABLES).unwrap();
+        run_migrations(&db);
+        assert_eq!(user_version(&db), MIGRATIONS.len() as i64);
+        // Adding the columns again would fail
+        run_migrations(&db);
+        assert_eq!(user_version(&db), MIGRATIONS.len() as i64);
+    }
+
+    #[test]
+    fn failed_migration_keeps_version() {
+        let db = sqlite::open(IN_MEMORY_DB_PATH).unwrap();
+        db.execute(SQL_CREATE_TABLES).unwrap();
+        run_migrations(&db);
+        // Like a step that was applied without its version, the column exists and the step fails
+        db.execute("PRAGMA user_version = 1").unwrap();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| run_migrations(&db)));
+        assert!(result.is_err());
+        assert_eq!(user_version(&db), 1);
+        // The transaction of the failed step is not left open
+        db.execute(SQL_BEGIN_IMMEDIATE).unwrap();
+        db.execute(SQL_ROLLBACK).unwrap();
+    }
+
+    #[test]
+    fn soft_delete_of_unknown_id_returns_zero() {
+        let storage = SqliteStorage::new_in_memory();
+        let (key, id) = &generate_keypairs(1)[0];
+        assert_eq!(storage.save_address(id, &registration(key, [1; 16], 5000, 7), true), 0);
+        assert!(storage.get_addresses(id).is_empty());
+    }
+
+    #[test]
+    fn addr_hash_ignores_ttl_and_priority() {
+        use std::collections::hash_map::DefaultHasher;
+        let hash = |addr: &Addr| {
+            let mut hasher = DefaultHasher::new();
+            addr.hash(&mut hasher);
+            hasher.finish()
+        };
+        let addr = Addr { ip: vec![1; 16], signature: vec![0; 64], port: 5000, priority: 1, client: 7, ttl: 600, latency_hint_ms: 0, flags: 0, signed_at: 0 };
+        let other = Addr { priority: 5, ttl: 60, ..addr.clone() };
+        assert_ne!(addr, other);
+        assert_eq!(hash(&addr), hash(&other));
+        assert_ne!(hash(&addr), hash(&Addr { port: 5001, ..addr.clone() }));
+    }
+
+    /// Storage with addresses of `count` IDs, the first one has two of them, returns the IDs in storage order
+    fn storage_with_ids(count: usize) -> (SqliteStorage, Vec<Vec<u8>>) {
+        let storage = SqliteStorage::new_in_memory();
+        let keys = generate_keypairs(count);
+        for (key, id) in keys.iter() {
+            storage.save_address(id, &registration(key, [1; 16], 5000, 7), false);
+        }
+        storage.save_address(&keys[0].1, &registration(&keys[0].0, [2; 16], 5000, 8), false);
+        let mut ids: Vec<Vec<u8>> = keys.iter().map(|(_, id)| id.to_vec()).collect();
+        ids.sort();
+        (storage, ids)
+    }
+
+    /// Walks all pages of `list_ids_paginated`, returns the IDs and the size of every page
+    fn walk_pages(storage: &SqliteStorage, limit: usize) -> (Vec<Vec<u8>>, Vec<usize>) {
+        let (mut ids, mut sizes, mut cursor) = (Vec::new(), Vec::new(), None);
+        loop {
+            let (page, next_cursor) = storage.list_ids_paginated(cursor, limit);
+            sizes.push(page.len());
+            ids.extend(page);
+            match next_cursor {
+                Some(next) => cursor = Some(next),
+                None => return (ids, sizes)
+            }
+        }
+    }
+
+    #[test]
+    fn paginated_ids_have_no_gaps_or_duplicates() {
+        let (storage, ids) = storage_with_ids(5);
+        assert_eq!(walk_pages(&storage, 2), (ids.clone(), vec![2, 2, 1]));
+        assert_eq!(walk_pages(&storage, 10), (ids, vec![5]));
+    }
+
+    #[test]
+    fn last_full_page_is_followed_by_empty_one() {
+        let (storage, ids) = storage_with_ids(4);
+        // The last page is full, so there can be more IDs after it
+        assert_eq!(walk_pages(&storage, 2), (ids, vec![2, 2, 0]));
+        assert_eq!(SqliteStorage::new_in_memory().list_ids_paginated(None, 2), (Vec::new(), None));
+    }
+
+    #[test]
+    fn all_ids_are_split_in_pages() {
+        let (storage, ids) = storage_with_ids(5);
+        let pages: Vec<Vec<Vec<u8>>> = (0..4).map(|page| storage.get_all_ids(page, 2)).collect();
+        assert_eq!(pages, vec![ids[0..2].to_vec(), ids[2..4].to_vec(), ids[4..].to_vec(), Vec::new()]);
+    }
+
+    #[test]
+    fn tombstones_since_are_oldest_first() {
+        let storage = SqliteStorage::new_in_memory();
+        for deleted_at in [100, 300, 200] {
+            assert!(storage.add_tombstone(&Tombstone { id: vec![1; 32], ip: vec![deleted_at as u8; 16], deleted_at, signature: vec![0; 64] }));
+        }
+        let deleted = |since| storage.get_tombstones_since(since).iter().map(|t| t.deleted_at).collect::<Vec<_>>();
+        assert_eq!(deleted(0), vec![100, 200, 300]);
+        assert_eq!(deleted(200), vec![200, 300]);
+        assert!(deleted(301).is_empty());
+    }
+}
@@ -0,0 +1,43 @@
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signer};
+use crate::functions::{deregistration_signed_data, full_deregistration_signed_data, registration_signed_data};
+use crate::storage::{ClientId, PortNum, Priority};
+
+/// Makes `n` keypairs with public keys, the secret key of keypair N is N in big endian in the first bytes.
+/// The same keys are made on every run, so benchmark results and IDs in their databases can be compared.
+pub fn generate_keypairs(n: usize) -> Vec<(Keypair, [u8; 32])> {
+    (0..n as u64)
+        .map(|index| {
+            let mut seed = [0u8; 32];
+            seed[..8].copy_from_slice(&index.to_be_bytes());
+            let secret = SecretKey::from_bytes(&seed).unwrap();
+            let public = PublicKey::from(&secret);
+            let id = public.to_bytes();
+            (Keypair { secret, public }, id)
+        })
+        .collect()
+}
+
+/// Signs `ip` as `check_ip_signature` expects it, `signed_at` is 0 for clients that sign only `ip`
+pub fn sign_ip(key: &Keypair, ip: [u8; 16], signed_at: u32) -> [u8; 64] {
+    let mut data = Vec::with_capacity(20);
+    if signed_at != 0 {
+        data.extend_from_slice(&signed_at.to_be_bytes());
+    }
+    data.extend_from_slice(&ip);
+    key.sign(&data).to_bytes()
+}
+
+/// Signs all fields of a registration as `check_registration_signature` expects it
+pub fn sign_registration(key: &Keypair, ip: [u8; 16], port: PortNum, priority: Priority, client: ClientId, signed_at: u32) -> [u8; 64] {
+    key.sign(&registration_signed_data(key.public.as_bytes(), &ip, port, priority, client, signed_at)).to_bytes()
+}
+
+/// Signs a deregistration of protocol version 2 as the server checks it
+pub fn sign_deregistration(key: &Keypair, ip: [u8; 16], client: ClientId, signed_at: u32) -> [u8; 64] {
+    key.sign(&deregistration_signed_data(key.public.as_bytes(), &ip, client, signed_at)).to_bytes()
+}
+
+/// Signs all fields of a deregistration of `REGISTRATION_SIGNATURE_VERSION` and later as the server checks it
+pub fn sign_full_deregistration(key: &Keypair, ip: [u8; 16], port: PortNum, priority: Priority, client: ClientId, flags: u8, signed_at: u32) -> [u8; 64] {
+    key.sign(&full_deregistration_signed_data(key.public.as_bytes(), &ip, port, priority, client, flags, signed_at)).to_bytes()
+}
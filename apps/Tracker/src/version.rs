@@ -0,0 +1,61 @@
+use std::fmt::{Display, Formatter};
+
+/// Version of the packet format this tracker speaks
+pub const PROTOCOL_VERSION: u8 = 3;
+/// Oldest packet format accepted by default, versions 0 and 1 share the layout without request timestamps
+pub const MIN_SUPPORTED_VERSION: u8 = 0;
+/// First protocol version that gets `latency_hint_ms` of every address in command-1 answers
+pub const LATENCY_HINT_VERSION: u8 = 2;
+/// First protocol version that gets `flags` of every address in command-1 answers
+pub const ADDR_FLAGS_VERSION: u8 = 2;
+/// First protocol version with `request_timestamp` u32 after nonce in all requests, also signed with `ip`
+pub const REQUEST_TIMESTAMP_VERSION: u8 = 2;
+/// First protocol version that signs port, priority and client of registrations too, see `registration_signed_data`
+pub const REGISTRATION_SIGNATURE_VERSION: u8 = 3;
+/// Oldest protocol version of accepted deregistrations, older ones have no request timestamp to sign
+pub const DEREGISTRATION_VERSION: u8 = 2;
+
+/// Vergen writes this instead of real values when it can't get them (no git, for example)
+const VERGEN_PLACEHOLDER: &str = "VERGEN_IDEMPOTENT_OUTPUT";
+
+pub struct Version {
+    pub crate_version: &'static str,
+    pub protocol_version: u8,
+    pub build_timestamp: &'static str,
+    pub git_sha: Option<&'static str>
+}
+
+impl Version {
+    /// Returns the version of this build
+    pub fn current() -> Self {
+        Version {
+            crate_version: env!("CARGO_PKG_VERSION"),
+            protocol_version: PROTOCOL_VERSION,
+            build_timestamp: env!("VERGEN_BUILD_TIMESTAMP"),
+            git_sha: option_env!("VERGEN_GIT_SHA").filter(|sha| *sha != VERGEN_PLACEHOLDER)
+        }
+    }
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "crate_version: {}", self.crate_version)?;
+        writeln!(f, "protocol_version: {}", self.protocol_version)?;
+        writeln!(f, "build_timestamp: {}", self.build_timestamp)?;
+        write!(f, "git_sha: {}", self.git_sha.unwrap_or("unknown"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_output_has_protocol_version() {
+        // `--version` prints this
+        let output = Version::current().to_string();
+        assert!(output.contains("protocol_version"));
+        assert!(output.contains(&format!("protocol_version: {}", PROTOCOL_VERSION)));
+        assert!(output.contains(env!("CARGO_PKG_VERSION")));
+    }
+}
@@ -0,0 +1,51 @@
+use std::process::abort;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::error;
+
+pub const DEFAULT_WATCHDOG_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct State {
+    started: Instant,
+    /// Milliseconds from `started` to the last kick
+    last_kick: AtomicU64,
+    stopped: AtomicBool
+}
+
+/// Stops the process if it is not kicked in time, so that systemd or supervisor can restart a stalled server
+pub struct WatchdogTimer {
+    state: Arc<State>
+}
+
+impl WatchdogTimer {
+    /// Starts the thread checking kicks, `name` is printed when the process is stopped
+    pub fn start(name: &str, timeout: Duration) -> Self {
+        let state = Arc::new(State { started: Instant::now(), last_kick: AtomicU64::new(0), stopped: AtomicBool::new(false) });
+        let checked = Arc::clone(&state);
+        let name = name.to_owned();
+        thread::spawn(move || {
+            while !checked.stopped.load(Ordering::Relaxed) {
+                thread::sleep(timeout / 4);
+                let since_kick = checked.started.elapsed().saturating_sub(Duration::from_millis(checked.last_kick.load(Ordering::Relaxed)));
+                if since_kick > timeout && !checked.stopped.load(Ordering::Relaxed) {
+                    // Panic would stop only this thread, the stalled one would stay
+                    error!("Server loop on {} stalled for >{}s", name, timeout.as_secs());
+                    abort();
+                }
+            }
+        });
+        WatchdogTimer { state }
+    }
+
+    pub fn kick(&self) {
+        self.state.last_kick.store(self.state.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+impl Drop for WatchdogTimer {
+    fn drop(&mut self) {
+        self.state.stopped.store(true, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,99 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use sqlite::{Connection, State};
+use tracker::storage::{get_utc_time, run_migrations, SqliteStorage, Storage};
+
+/// Schema of the first released version, before any migration
+const ORIGINAL_SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS clients (
+    'id' BLOB NOT NULL,
+    'ip' BLOB NOT NULL,
+    'signature' BLOB NOT NULL,
+    'port' INTEGER,
+    'priority' INTEGER,
+    'client' INTEGER,
+    'timestamp' INTEGER,
+    'ttl' INTEGER
+);
+CREATE INDEX IF NOT EXISTS id_index ON clients (id);";
+
+/// Removed on drop, so that failed asserts don't leave files behind
+struct TempDb(PathBuf);
+
+impl TempDb {
+    fn new(name: &str) -> Self {
+        TempDb(env::temp_dir().join(format!("mimir-migration-{}-{}.sqlite", name, std::process::id())))
+    }
+
+    fn path(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+}
+
+impl Drop for TempDb {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Old database with addresses of IDs 1 and 2, ID 1 has two rows of client 7 like older versions could save
+fn create_old_db(path: &str) -> Connection {
+    let db = sqlite::open(path).unwrap();
+    db.execute(ORIGINAL_SCHEMA).unwrap();
+    let now = get_utc_time() as i64;
+    for (id, ip, port, client) in [(1u8, 1u8, 5000, 7), (1, 2, 5001, 7), (1, 3, 5002, 8), (2, 4, 6000, 7)] {
+        let mut statement = db.prepare("INSERT INTO clients (id, ip, signature, port, priority, client, timestamp, ttl) VALUES (?, ?, ?, ?, 1, ?, ?, 3600)").unwrap();
+        statement.bind((1, [id; 32].as_slice())).unwrap();
+        statement.bind((2, [ip; 16].as_slice())).unwrap();
+        statement.bind((3, [0u8; 64].as_slice())).unwrap();
+        statement.bind((4, port)).unwrap();
+        statement.bind((5, client)).unwrap();
+        statement.bind((6, now)).unwrap();
+        statement.next().unwrap();
+    }
+    db
+}
+
+fn select_i64(db: &Connection, sql: &str) -> Vec<i64> {
+    let mut statement = db.prepare(sql).unwrap();
+    let mut result = Vec::new();
+    while statement.next().unwrap() == State::Row {
+        result.push(statement.read(0).unwrap());
+    }
+    result
+}
+
+#[test]
+fn migrations_keep_rows_of_old_db() {
+    let file = TempDb::new("rows");
+    let db = create_old_db(file.path());
+    run_migrations(&db);
+
+    // Only the newest row of a client is kept, the other rows are all there
+    assert_eq!(select_i64(&db, "SELECT port FROM clients ORDER BY port"), vec![5001, 5002, 6000]);
+    // New columns have their defaults in old rows
+    for column in ["latency_hint", "flags", "signed_at"] {
+        assert_eq!(select_i64(&db, &format!("SELECT {} FROM clients", column)), vec![0; 3], "{}", column);
+    }
+    let version = select_i64(&db, "PRAGMA user_version");
+    run_migrations(&db);
+    assert_eq!(select_i64(&db, "PRAGMA user_version"), version);
+}
+
+#[test]
+fn migrated_db_works_with_storage() {
+    let file = TempDb::new("storage");
+    drop(create_old_db(file.path()));
+    let storage = SqliteStorage::new(file.path());
+
+    let addrs = storage.get_addresses(&[1; 32]);
+    assert_eq!(addrs.len(), 2);
+    assert!(addrs.iter().all(|addr| addr.latency_hint_ms == 0 && addr.flags == 0 && addr.signed_at == 0));
+    assert_eq!(storage.get_addresses_for_client(&[2; 32], 7)[0].port, 6000);
+    assert_eq!(storage.count_addresses_for_id(&[1; 32]), 2);
+    // Rows of the old schema can be updated and removed with the new one
+    assert!(storage.touch(&[2; 32], &[4; 16], 7).is_some());
+    assert_eq!(storage.prune_id(&[1; 32]), 2);
+    assert!(storage.get_addresses(&[1; 32]).is_empty());
+}